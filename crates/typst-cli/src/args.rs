@@ -138,6 +138,10 @@ pub struct InitCommand {
 }
 
 /// Processes an input file to extract provided metadata
+///
+/// The input may also be a glob pattern like `"docs/**/*.typ"`. Then, every
+/// matching file is compiled and queried on its own and the results are
+/// grouped by file path.
 #[derive(Debug, Clone, Parser)]
 pub struct QueryCommand {
     /// Shared arguments
@@ -145,30 +149,772 @@ pub struct QueryCommand {
     pub common: SharedArgs,
 
     /// Defines which elements to retrieve
-    pub selector: String,
+    #[clap(
+        required_unless_present_any = [
+            "list_selectors",
+            "document_info",
+            "text",
+            "bibliography",
+            "raw",
+            "location",
+            "capabilities",
+            "selector_file",
+            "selectors",
+        ]
+    )]
+    pub selector: Option<String>,
+
+    /// The syntax that selectors are written in
+    #[clap(long = "selector-dialect", default_value = "typst")]
+    pub selector_dialect: SelectorDialect,
+
+    /// Retrieves the element at an introspection location instead of
+    /// evaluating a selector
+    ///
+    /// The location is given by the `id` that `--with-location` outputs. It
+    /// stays the same across compilations as long as the document doesn't
+    /// change before the element.
+    #[clap(
+        long = "location",
+        value_name = "ID",
+        conflicts_with_all = ["selector", "selector_file", "selectors"]
+    )]
+    pub location: Option<String>,
+
+    /// Defines which elements to retrieve, can be given multiple times
+    ///
+    /// Instead of a flat list of elements, this outputs a dictionary mapping
+    /// each selector to its matches.
+    #[clap(
+        long = "selector",
+        value_name = "SELECTOR",
+        conflicts_with_all = ["selector", "selector_file", "one", "count", "render"]
+    )]
+    pub selectors: Vec<String>,
+
+    /// Defines a named selector that other selectors can refer to
+    ///
+    /// Definitions are evaluated in order, so later ones can refer to earlier
+    /// ones.
+    #[clap(
+        long = "define",
+        value_name = "name=selector",
+        action = ArgAction::Append,
+        value_parser = ValueParser::new(parse_input_pair),
+    )]
+    pub definitions: Vec<(String, String)>,
+
+    /// Reads the selector from a file instead of the command line
+    #[clap(long = "selector-file", value_name = "PATH")]
+    pub selector_file: Option<PathBuf>,
+
+    /// Also queries these files, each compiled as a document of its own
+    ///
+    /// The results for all files are merged, and each one is tagged with the
+    /// `input` file it stems from. If the files are parts of one document,
+    /// querying a main file that includes them with `#include` is preferable.
+    #[clap(
+        long = "also",
+        value_name = "PATH",
+        conflicts_with_all = [
+            "selectors",
+            "one",
+            "index",
+            "count",
+            "check",
+            "explain",
+            "schema",
+            "render",
+            "list_selectors",
+            "watch",
+        ]
+    )]
+    pub also: Vec<PathBuf>,
+
+    /// Extracts just the given fields from all retrieved elements
+    ///
+    /// Nested fields can be reached with a dot-separated path, e.g.
+    /// `value.title`. Besides an element's own fields, `text` yields its plain
+    /// text and `location` its page and position. Real fields of the same name
+    /// take precedence.
+    ///
+    /// Multiple fields can be separated by commas or given by repeating the
+    /// flag. Each element then yields a dictionary from the fields' paths to
+    /// their values.
+    #[clap(long = "field", value_delimiter = ',')]
+    pub field: Vec<String>,
+
+    /// Outputs `none` for fields an element doesn't have instead of skipping
+    /// the element
+    #[clap(long = "null-missing-field", requires = "field")]
+    pub null_missing_field: bool,
+
+    /// Serializes retrieved elements with just the given comma-separated
+    /// fields
+    ///
+    /// Unlike with `--field`, the elements keep their shape, including their
+    /// `func`. Fields an element doesn't have are omitted.
+    #[clap(
+        long = "only-fields",
+        value_name = "FIELDS",
+        value_delimiter = ',',
+        conflicts_with = "field"
+    )]
+    pub only_fields: Vec<String>,
+
+    /// Adds the page, file, line, column, and location id of each retrieved
+    /// element to the output, under a `location` key
+    ///
+    /// Elements gain the key next to their fields, while extracted fields are
+    /// wrapped in a dictionary with `value` and `location` keys.
+    #[clap(long = "with-location", conflicts_with = "list_selectors")]
+    pub with_location: bool,
+
+    /// Adds the package each retrieved element stems from to the output,
+    /// under a `package` key
+    ///
+    /// The package is given by its namespace, name, and version, or is `none`
+    /// for elements from the project itself. Like with `--with-location`,
+    /// extracted fields are wrapped in a dictionary.
+    #[clap(long = "with-package", conflicts_with = "list_selectors")]
+    pub with_package: bool,
+
+    /// Adds the text of the paragraph each retrieved footnote is referenced
+    /// from to the output, under a `reference` key
+    ///
+    /// The footnote's own body is left out of the text. Elements other than
+    /// footnotes get `none`. Like with `--with-location`, extracted fields are
+    /// wrapped in a dictionary.
+    #[clap(long = "with-ref", conflicts_with = "list_selectors")]
+    pub with_ref: bool,
+
+    /// Adds a hash of each retrieved element's serialized value to the
+    /// output, under a `hash` key
+    ///
+    /// The hash only depends on the value itself, not on the other keys added
+    /// to it, and is the same across runs and platforms. This lets caches skip
+    /// elements that didn't change between revisions of a document.
+    #[clap(long = "with-hash", conflicts_with = "list_selectors")]
+    pub with_hash: bool,
+
+    /// Removes retrieved elements that are hidden from view from the output
+    ///
+    /// An element counts as hidden if it is written within `hide`, also when
+    /// it's nested in other content there, like in `place`. Elements that
+    /// show rules create within hidden content are not detected. Metadata is
+    /// invisible by design and never counts as hidden, so it is always kept.
+    #[clap(long = "exclude-hidden")]
+    pub exclude_hidden: bool,
+
+    /// Removes retrieved elements that are identical to an earlier one
+    #[clap(long = "unique")]
+    pub unique: bool,
+
+    /// Sorts the retrieved elements by the value of a field
+    ///
+    /// The field is given like with `--field`. Elements without the field come
+    /// last. Elements with equal values keep their order in the document.
+    #[clap(long = "sort-by", value_name = "FIELD")]
+    pub sort_by: Option<String>,
+
+    /// Groups the retrieved elements by the value of a field
+    ///
+    /// The field is given like with `--field`. This outputs a dictionary from
+    /// each distinct value to the elements that have it, in the order in which
+    /// the values first occur. Elements without the field are grouped under
+    /// `null`.
+    #[clap(
+        long = "group-by",
+        value_name = "FIELD",
+        conflicts_with_all = [
+            "selectors",
+            "one",
+            "index",
+            "count",
+            "explain",
+            "render",
+            "tree",
+            "key_by_label",
+            "list_selectors",
+            "also",
+        ]
+    )]
+    pub group_by: Option<String>,
 
-    /// Extracts just one field from all retrieved elements
-    #[clap(long = "field")]
-    pub field: Option<String>,
+    /// Reverses the order given by `--sort-by`
+    #[clap(long = "reverse", requires = "sort_by")]
+    pub reverse: bool,
+
+    /// The exit code to return if no element was retrieved
+    #[clap(long = "if-empty", value_name = "CODE", default_value_t = 0)]
+    pub if_empty: u8,
+
+    /// The exit code to return if at least one element was retrieved
+    #[clap(long = "if-found", value_name = "CODE", default_value_t = 0)]
+    pub if_found: u8,
+
+    /// Prints the size, baseline, and number of items of each rendered
+    /// element's frame to stderr, as JSON
+    ///
+    /// Sizes are given in points.
+    #[clap(long = "emit-geometry", requires = "render")]
+    pub emit_geometry: bool,
+
+    /// Outputs a dictionary from the labels of the retrieved elements to the
+    /// elements instead of an array
+    ///
+    /// Elements without a label are left out. This fails if a label is
+    /// attached to more than one retrieved element.
+    #[clap(
+        long = "key-by-label",
+        conflicts_with_all = [
+            "selectors",
+            "one",
+            "index",
+            "count",
+            "explain",
+            "render",
+            "tree",
+            "list_selectors",
+            "also",
+        ]
+    )]
+    pub key_by_label: bool,
+
+    /// Nests the retrieved headings by their level, like in an outline
+    ///
+    /// Each heading gains a `children` array with the headings below it. This
+    /// fails if the selector matches anything other than headings.
+    #[clap(
+        long = "tree",
+        conflicts_with_all = [
+            "selectors",
+            "one",
+            "index",
+            "count",
+            "explain",
+            "render",
+            "list_selectors",
+            "also",
+        ]
+    )]
+    pub tree: bool,
+
+    /// Prints a human-readable description of each retrieved element instead
+    /// of serializing it
+    ///
+    /// The description includes the element's kind, label, page, and the
+    /// source code it stems from.
+    #[clap(
+        long = "explain",
+        conflicts_with_all = [
+            "selectors",
+            "field",
+            "one",
+            "with_location",
+            "count",
+            "render",
+            "list_selectors",
+        ]
+    )]
+    pub explain: bool,
+
+    /// Prints one line per retrieved element instead of serializing it
+    ///
+    /// Each line has the form `<page>:<kind> <label> — <text>`, where the
+    /// label is left out if the element has none. When writing to a terminal,
+    /// the text is cut to fit 80 characters or the `--summary-width`.
+    #[clap(
+        long = "summary",
+        conflicts_with_all = [
+            "selectors",
+            "field",
+            "one",
+            "with_location",
+            "count",
+            "explain",
+            "render",
+            "list_selectors",
+            "also",
+        ]
+    )]
+    pub summary: bool,
+
+    /// The number of characters to cut the lines of `--summary` to
+    ///
+    /// Without this, lines are only cut when writing to a terminal.
+    #[clap(long = "summary-width", value_name = "COLUMNS", requires = "summary")]
+    pub summary_width: Option<usize>,
+
+    /// Outputs a JSON Schema describing the serialized form of the retrieved
+    /// elements instead of the elements themselves
+    ///
+    /// The schema is derived from the fields of the elements' functions.
+    #[clap(
+        long = "schema",
+        conflicts_with_all = [
+            "selectors",
+            "field",
+            "with_location",
+            "count",
+            "explain",
+            "render",
+            "list_selectors",
+        ]
+    )]
+    pub schema: bool,
+
+    /// Only checks that the document compiles and the selector is valid,
+    /// printing the number of matches to stderr instead of writing any output
+    #[clap(
+        long = "check",
+        conflicts_with_all = [
+            "field",
+            "one",
+            "with_location",
+            "count",
+            "explain",
+            "schema",
+            "render",
+            "list_selectors",
+        ]
+    )]
+    pub check: bool,
+
+    /// Prints only the number of retrieved elements
+    #[clap(
+        long = "count",
+        conflicts_with_all = ["field", "one", "with_location", "render", "list_selectors"]
+    )]
+    pub count: bool,
+
+    /// Outputs at most this many elements, warning if more were retrieved
+    ///
+    /// This doesn't affect `--count` and `--check`, which still report the
+    /// total number of matches.
+    #[clap(long = "max-matches", value_name = "N")]
+    pub max_matches: Option<usize>,
 
     /// Expects and retrieves exactly one element
     #[clap(long = "one", default_value = "false")]
     pub one: bool,
 
+    /// Retrieves only the element at this zero-based index among the matches
+    ///
+    /// Negative indices count from the end, so `-1` selects the last match.
+    #[clap(
+        long = "index",
+        value_name = "N",
+        allow_hyphen_values = true,
+        conflicts_with_all = [
+            "one",
+            "selectors",
+            "count",
+            "render",
+            "explain",
+            "list_selectors",
+        ]
+    )]
+    pub index: Option<i64>,
+
+    /// Lists the kinds of elements present in the document instead of
+    /// retrieving elements, along with how many of each there are
+    ///
+    /// Only elements that can be queried are listed.
+    #[clap(
+        long = "list-selectors",
+        conflicts_with_all = [
+            "selector",
+            "selector_file",
+            "selectors",
+            "field",
+            "one",
+            "render",
+        ]
+    )]
+    pub list_selectors: bool,
+
+    /// Prints a JSON object describing the supported output formats and
+    /// options instead of querying anything
+    ///
+    /// This lets tools adapt to the installed version of Typst. The input
+    /// isn't read, so `-` can be passed as a placeholder.
+    #[clap(long = "capabilities")]
+    pub capabilities: bool,
+
+    /// Outputs information about the document itself instead of retrieving
+    /// elements
+    ///
+    /// This includes the number of pages, their sizes in points, and the
+    /// title, author, and keywords set with `set document(..)`.
+    #[clap(
+        long = "document-info",
+        conflicts_with_all = [
+            "selector",
+            "selector_file",
+            "selectors",
+            "field",
+            "one",
+            "render",
+            "list_selectors",
+        ]
+    )]
+    pub document_info: bool,
+
+    /// Outputs the plain text of the whole document instead of retrieving
+    /// elements
+    ///
+    /// Paragraphs and headings are separated by blank lines. Like with
+    /// `--field text`, the text is taken from the document's content before
+    /// show rules are applied.
+    #[clap(
+        long = "text",
+        conflicts_with_all = [
+            "selector",
+            "selector_file",
+            "selectors",
+            "field",
+            "one",
+            "render",
+            "list_selectors",
+            "document_info",
+        ]
+    )]
+    pub text: bool,
+
+    /// Outputs the entries of the document's bibliography instead of
+    /// retrieving elements
+    ///
+    /// Each entry is given by its citation `key`, its `prefix` (like `[1]`),
+    /// if the citation style has one, and its formatted `text`, in the order
+    /// they are listed in the bibliography.
+    #[clap(
+        long = "bibliography",
+        conflicts_with_all = [
+            "selector",
+            "selector_file",
+            "selectors",
+            "field",
+            "one",
+            "render",
+            "list_selectors",
+            "document_info",
+            "text",
+        ]
+    )]
+    pub bibliography: bool,
+
+    /// Outputs the raw text of the document, like code blocks, instead of
+    /// retrieving elements
+    ///
+    /// Each raw text is given by its `lang`, if any, whether it is a `block`,
+    /// and its `text`, in document order. Like with `--text`, they are taken
+    /// from the document's content before show rules are applied, so raw
+    /// text that show rules create isn't included and a language set with
+    /// `set raw(lang: ..)` isn't picked up.
+    #[clap(
+        long = "raw",
+        conflicts_with_all = [
+            "selector",
+            "selector_file",
+            "selectors",
+            "field",
+            "one",
+            "render",
+            "list_selectors",
+            "document_info",
+            "text",
+            "bibliography",
+        ]
+    )]
+    pub raw: bool,
+
+    /// Which pages to retrieve elements from. When unspecified, elements on
+    /// all pages are retrieved.
+    ///
+    /// Uses the same syntax as `typst compile --pages`, e.g. '3', '2-5' or
+    /// '4-'. Pages outside of the document simply yield no elements.
+    #[arg(long = "pages", value_delimiter = ',')]
+    pub pages: Option<Vec<PageRangeArgument>>,
+
+    /// Only retrieves elements that are nested in at least this many other
+    /// elements
+    ///
+    /// An element counts as nested in another one if it is part of the other
+    /// one's content, like a figure in the body of another figure.
+    #[clap(long = "min-depth", value_name = "N")]
+    pub min_depth: Option<usize>,
+
+    /// Only retrieves elements that are nested in at most this many other
+    /// elements
+    ///
+    /// With `--max-depth 0`, only top-level elements are retrieved.
+    #[clap(long = "max-depth", value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// Fails if more than one element was retrieved, but still outputs all of
+    /// them
+    ///
+    /// Unlike `--one`, this doesn't change the shape of the output. With
+    /// `--selector`, each selector is checked on its own.
+    #[clap(long = "fail-on-multiple")]
+    pub fail_on_multiple: bool,
+
+    /// Also writes the diagnostics to the output if the document fails to
+    /// compile
+    ///
+    /// The diagnostics are serialized in the chosen format as an array of
+    /// dictionaries with the same keys as with `--diagnostic-format json`, so
+    /// that tools can read both results and failures from the same stream.
+    #[clap(long = "errors-as-output")]
+    pub errors_as_output: bool,
+
+    /// Fails if compiling the document produced any warnings
+    #[clap(long = "deny-warnings")]
+    pub deny_warnings: bool,
+
+    /// Watches the input files and re-runs the query whenever they change
+    ///
+    /// If only the selector file changes, the previously compiled document is
+    /// queried again without recompiling it.
+    #[clap(long = "watch")]
+    pub watch: bool,
+
+    /// Writes a stream of JSON events to stdout while watching, one per line
+    ///
+    /// Each time the query re-runs, a `results` event with a `timestamp`, the
+    /// match `count`, and the serialized `results` is written. Failures are
+    /// written as `error` events with a `message` and, if compilation failed,
+    /// the `diagnostics`, so the stream continues after them. The events are
+    /// always JSON, regardless of `--format`.
+    #[clap(long = "events", requires = "watch", conflicts_with = "output")]
+    pub events: bool,
+
+    /// Prints the status of the compilation to stderr, like `typst watch`
+    /// does
+    ///
+    /// This reports when compilation starts and how it ended, including the
+    /// time it took.
+    #[clap(long = "progress")]
+    pub progress: bool,
+
+    /// Prints how long compilation, retrieval, and serialization took to
+    /// stderr
+    #[clap(long = "timings")]
+    pub timings: bool,
+
+    /// Wraps the serialized results in a dictionary that also holds their
+    /// count and how long compilation and retrieval took
+    ///
+    /// The results are under `matches` and the rest under `meta`, with the
+    /// count as `count` and the durations in milliseconds as `compile_ms`
+    /// and `retrieve_ms`. The count includes matches left out with
+    /// `--max-matches`, in which case `truncated` is `true`.
+    #[clap(
+        long = "envelope",
+        conflicts_with_all = [
+            "selectors",
+            "also",
+            "count",
+            "check",
+            "explain",
+            "summary",
+            "schema",
+            "render",
+            "tree",
+            "key_by_label",
+            "group_by",
+            "list_selectors",
+        ]
+    )]
+    pub envelope: bool,
+
+    /// Prints the number of elements, distinct labels, and pages of the
+    /// document to stderr
+    #[clap(long = "stats")]
+    pub stats: bool,
+
+    /// Prints a table of how often each kind of element occurs in the
+    /// document to stderr, most frequent first
+    ///
+    /// Only elements that can be queried are counted.
+    #[clap(long = "profile")]
+    pub profile: bool,
+
     /// The format to serialize in
     #[clap(long = "format", default_value = "json")]
     pub format: SerializationFormat,
 
     /// Whether to pretty-print the serialized output
+    ///
+    /// Without it, JSON is emitted on a single line, while YAML is still
+    /// emitted in block style. JSON Lines are never pretty-printed.
     #[clap(long)]
     pub pretty: bool,
+
+    /// Whether to emit the serialized output as compactly as possible
+    ///
+    /// This emits YAML on a single line in flow style. JSON and TOML are
+    /// already compact without `--pretty`.
+    #[clap(long, conflicts_with = "pretty")]
+    pub compact: bool,
+
+    /// Compares the serialized output against this file instead of writing
+    /// it, failing with a line diff if they differ
+    ///
+    /// The plain text output of `--count`, `--summary`, `--explain`, and
+    /// `--text` is compared in the same way. Together with `--update-baseline`, this allows checking that the
+    /// results of a query didn't change.
+    #[clap(long = "baseline", value_name = "FILE", conflicts_with = "output")]
+    pub baseline: Option<PathBuf>,
+
+    /// Writes the output to the `--baseline` file instead of
+    /// comparing against it
+    #[clap(long = "update-baseline", requires = "baseline")]
+    pub update_baseline: bool,
+
+    /// How to serialize integers and floats
+    #[clap(long = "number-mode", default_value = "native")]
+    pub number_mode: NumberMode,
+
+    /// Rounds floats and lengths in the output to this many decimal places
+    ///
+    /// Lengths are rounded in points, as which they are serialized. By
+    /// default, numbers are serialized with full precision.
+    #[clap(long = "float-precision", value_name = "N")]
+    pub float_precision: Option<u8>,
+
+    /// Renders retrieved elements to images instead of serializing them
+    #[clap(long = "render")]
+    pub render: bool,
+
+    /// Path to the output file. Use `-` to write output to stdout.
+    ///
+    /// Serialized data is written to stdout by default. Rendered elements
+    /// default to the input file's name with an `.svg` extension. Rendering
+    /// produces a PNG or PDF if the path ends in `.png` or `.pdf` and an SVG
+    /// otherwise. Missing parent directories are created.
+    ///
+    /// To render all retrieved elements instead of only the first, add `{n}` to
+    /// the path. It is replaced by the zero-based index of each element.
+    #[clap(long = "output", value_parser = ValueParser::new(output_value_parser))]
+    pub output: Option<Output>,
+
+    /// Renders every retrieved element to its own file in this directory,
+    /// which is created if it doesn't exist
+    ///
+    /// The files are named after `--name-template`.
+    #[clap(
+        long = "output-dir",
+        value_name = "DIR",
+        requires = "render",
+        conflicts_with_all = ["output", "data_uri"]
+    )]
+    pub output_dir: Option<PathBuf>,
+
+    /// The name of each file written to `--output-dir`
+    ///
+    /// `{index}` is replaced by the zero-based index of the element, `{label}`
+    /// by its label, and `{page}` by the page it is on. An element without a
+    /// label is named `unlabeled-{index}` in place of its label, and characters
+    /// in labels that are unsafe in file names are replaced with `_`. Like
+    /// with `--output`, the extension determines the format.
+    #[clap(
+        long = "name-template",
+        value_name = "TEMPLATE",
+        default_value = "{index}.svg",
+        requires = "output_dir"
+    )]
+    pub name_template: String,
+
+    /// The PPI (pixels per inch) to use for PNG rendering
+    #[arg(long = "ppi", default_value_t = 144.0)]
+    pub ppi: f32,
+
+    /// How to size the page that elements are rendered on
+    #[arg(long = "fit", default_value = "page")]
+    pub fit: RenderFit,
+
+    /// Prints each rendered element as a base64 data URI to stdout instead
+    /// of writing it to a file
+    ///
+    /// The URI can be pasted straight into HTML or Markdown. Elements are
+    /// rendered as SVG unless PNG is requested.
+    #[clap(
+        long = "data-uri",
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        default_missing_value = "svg",
+        requires = "render",
+        conflicts_with = "output"
+    )]
+    pub data_uri: Option<DataUriFormat>,
+
+    /// Which page to render if an element spans multiple pages, starting at
+    /// one
+    #[arg(long = "render-page", value_name = "N", default_value = "1")]
+    pub render_page: NonZeroUsize,
+
+    /// The background color of rendered elements
+    ///
+    /// Accepts a hex color like `#ffffff`, a named color like `white`, or
+    /// `none` for a transparent background.
+    #[arg(long = "background", value_name = "COLOR", default_value = "none")]
+    pub background: String,
 }
 
 // Output file format for query command
 #[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
 pub enum SerializationFormat {
     Json,
+    /// JSON Lines: Each retrieved element as compact JSON on its own line.
+    Jsonl,
     Yaml,
+    Toml,
+    Cbor,
+    /// MessagePack: A compact binary format.
+    #[value(name = "msgpack")]
+    MsgPack,
+    /// Comma-separated values: One row per retrieved element, which must be a
+    /// flat dictionary, e.g. extracted with `--field`. Columns follow the order
+    /// of the dictionary's keys.
+    Csv,
+}
+
+/// How the query command sizes the page of a rendered element.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum RenderFit {
+    /// The size of the page the element is on in the document.
+    Page,
+    /// Exactly the size of the element, without margins.
+    Content,
+}
+
+/// The syntax of the query command's selectors.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum SelectorDialect {
+    /// Typst code, like `heading.where(level: 1)`.
+    Typst,
+    /// An experimental CSS-like syntax, like `heading[level=1]`, `figure.image`,
+    /// or `#intro`, with alternatives separated by commas.
+    Css,
+}
+
+/// Which image format the query command embeds in a data URI.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum DataUriFormat {
+    Svg,
+    Png,
+}
+
+/// How numbers are serialized by the query command.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, ValueEnum)]
+pub enum NumberMode {
+    /// As numbers of the output format.
+    Native,
+    /// As strings holding their exact decimal representation, so that they
+    /// aren't rounded by consumers that parse all numbers as floats.
+    String,
 }
 
 /// Common arguments of compile, watch, and query.
@@ -238,6 +984,12 @@ pub struct PackageStorageArgs {
         value_name = "DIR"
     )]
     pub package_cache_path: Option<PathBuf>,
+
+    /// Never downloads packages, failing if a package isn't available locally
+    ///
+    /// This keeps builds hermetic, for example in CI.
+    #[clap(long = "offline", env = "TYPST_OFFLINE")]
+    pub offline: bool,
 }
 
 /// Parses a UNIX timestamp according to <https://reproducible-builds.org/specs/source-date-epoch/>
@@ -287,7 +1039,8 @@ fn input_value_parser(value: &str) -> Result<Input, clap::error::Error> {
     }
 }
 
-/// The clap value parser used by `CompileCommand.output`
+/// The clap value parser used by `CompileCommand.output` and
+/// `QueryCommand.output`
 fn output_value_parser(value: &str) -> Result<Output, clap::error::Error> {
     // Empty value also handled by clap for `Option<Output>`
     if value.is_empty() {
@@ -299,7 +1052,8 @@ fn output_value_parser(value: &str) -> Result<Output, clap::error::Error> {
     }
 }
 
-/// Parses key/value pairs split by the first equal sign.
+/// Parses key/value pairs split by the first equal sign, used by
+/// `SharedArgs.inputs` and `QueryCommand.definitions`.
 ///
 /// This function will return an error if the argument contains no equals sign
 /// or contains the key (before the equals sign) is empty.
@@ -316,8 +1070,9 @@ fn parse_input_pair(raw: &str) -> Result<(String, String), String> {
 }
 
 /// Implements parsing of page ranges (`1-3`, `4`, `5-`, `-2`), used by the
-/// `CompileCommand.pages` argument, through the `FromStr` trait instead of
-/// a value parser, in order to generate better errors.
+/// `CompileCommand.pages` and `QueryCommand.pages` arguments, through the
+/// `FromStr` trait instead of a value parser, in order to generate better
+/// errors.
 ///
 /// See also: https://github.com/clap-rs/clap/issues/5065
 #[derive(Debug, Clone)]
@@ -392,6 +1147,14 @@ pub struct FontArgs {
     /// `--font-path`
     #[arg(long)]
     pub ignore_system_fonts: bool,
+
+    /// Caches the index of discovered fonts in this file
+    ///
+    /// If the file exists and the font directories haven't changed since it
+    /// was written, fonts aren't searched again. Otherwise, the file is
+    /// updated after searching.
+    #[clap(long = "font-cache", env = "TYPST_FONT_CACHE", value_name = "FILE")]
+    pub font_cache: Option<PathBuf>,
 }
 
 /// Which format to use for diagnostics.
@@ -399,6 +1162,8 @@ pub struct FontArgs {
 pub enum DiagnosticFormat {
     Human,
     Short,
+    /// A JSON array with one object per diagnostic, for use by tooling.
+    Json,
 }
 
 impl Display for DiagnosticFormat {
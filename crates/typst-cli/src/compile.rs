@@ -8,6 +8,7 @@ use codespan_reporting::term;
 use ecow::{eco_format, eco_vec, EcoString, EcoVec};
 use parking_lot::RwLock;
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use serde::Serialize;
 use typst::diag::{bail, FileError, Severity, SourceDiagnostic, StrResult, Warned};
 use typst::foundations::{Datetime, Smart};
 use typst::layout::{Frame, PageRanges};
@@ -193,7 +194,7 @@ fn export_pdf(document: &Document, command: &CompileCommand) -> StrResult<()> {
 }
 
 /// Convert [`chrono::DateTime`] to [`Datetime`]
-fn convert_datetime(date_time: chrono::DateTime<chrono::Utc>) -> Option<Datetime> {
+pub fn convert_datetime(date_time: chrono::DateTime<chrono::Utc>) -> Option<Datetime> {
     Datetime::from_ymd_hms(
         date_time.year(),
         date_time.month().try_into().ok()?,
@@ -349,13 +350,21 @@ fn export_image_page(
 }
 
 impl Output {
-    fn write(&self, buffer: &[u8]) -> StrResult<()> {
+    pub fn write(&self, buffer: &[u8]) -> StrResult<()> {
         match self {
             Output::Stdout => std::io::stdout().write_all(buffer),
             Output::Path(path) => fs::write(path, buffer),
         }
         .map_err(|err| eco_format!("{err}"))
     }
+
+    /// Open the output for writing to it incrementally.
+    pub fn open(&self) -> io::Result<Box<dyn Write>> {
+        Ok(match self {
+            Output::Stdout => Box::new(io::stdout().lock()),
+            Output::Path(path) => Box::new(io::BufWriter::new(File::create(path)?)),
+        })
+    }
 }
 
 /// Caches exported files so that we can avoid re-exporting them if they haven't
@@ -484,7 +493,7 @@ fn open_file(open: Option<&str>, path: &Path) -> StrResult<()> {
 
 /// Adds useful hints when the main source file couldn't be read
 /// and returns the final diagnostic.
-fn hint_invalid_main_file(
+pub fn hint_invalid_main_file(
     file_error: FileError,
     input: &Input,
 ) -> EcoVec<SourceDiagnostic> {
@@ -535,6 +544,10 @@ pub fn print_diagnostics(
     warnings: &[SourceDiagnostic],
     diagnostic_format: DiagnosticFormat,
 ) -> Result<(), codespan_reporting::files::Error> {
+    if diagnostic_format == DiagnosticFormat::Json {
+        return print_json_diagnostics(world, errors, warnings);
+    }
+
     let mut config = term::Config { tab_width: 2, ..Default::default() };
     if diagnostic_format == DiagnosticFormat::Short {
         config.display_style = term::DisplayStyle::Short;
@@ -571,6 +584,72 @@ pub fn print_diagnostics(
     Ok(())
 }
 
+/// Print diagnostic messages to the terminal as a JSON array.
+fn print_json_diagnostics(
+    world: &SystemWorld,
+    errors: &[SourceDiagnostic],
+    warnings: &[SourceDiagnostic],
+) -> Result<(), codespan_reporting::files::Error> {
+    if errors.is_empty() && warnings.is_empty() {
+        return Ok(());
+    }
+
+    let diagnostics = json_diagnostics(world, errors, warnings);
+    let mut out = terminal::out();
+    serde_json::to_writer(&mut out, &diagnostics).map_err(io::Error::from)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+/// Convert diagnostics into their machine-readable form, warnings first.
+pub(crate) fn json_diagnostics(
+    world: &SystemWorld,
+    errors: &[SourceDiagnostic],
+    warnings: &[SourceDiagnostic],
+) -> Vec<JsonDiagnostic> {
+    warnings
+        .iter()
+        .chain(errors)
+        .map(|diagnostic| JsonDiagnostic::new(world, diagnostic))
+        .collect()
+}
+
+/// A diagnostic in a machine-readable form.
+#[derive(Serialize)]
+pub(crate) struct JsonDiagnostic {
+    severity: &'static str,
+    message: EcoString,
+    hints: Vec<EcoString>,
+    /// The file the diagnostic points into, if any.
+    file: Option<String>,
+    /// The byte offset at which the diagnostic's span starts.
+    start: Option<usize>,
+    /// The byte offset at which the diagnostic's span ends.
+    end: Option<usize>,
+}
+
+impl JsonDiagnostic {
+    fn new(world: &SystemWorld, diagnostic: &SourceDiagnostic) -> Self {
+        let severity = match diagnostic.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let file = diagnostic
+            .span
+            .id()
+            .and_then(|id| codespan_reporting::files::Files::name(world, id).ok());
+        let range = world.range(diagnostic.span);
+        Self {
+            severity,
+            message: diagnostic.message.clone(),
+            hints: diagnostic.hints.to_vec(),
+            file,
+            start: range.as_ref().map(|range| range.start),
+            end: range.map(|range| range.end),
+        }
+    }
+}
+
 /// Create a label for a span.
 fn label(world: &SystemWorld, span: Span) -> Option<Label<FileId>> {
     Some(Label::primary(span.id()?, world.range(span)?))
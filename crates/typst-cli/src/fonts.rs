@@ -1,18 +1,22 @@
+use std::collections::BTreeSet;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
+use std::time::SystemTime;
 
+use ecow::eco_format;
 use fontdb::{Database, Source};
+use serde::{Deserialize, Serialize};
 use typst::diag::StrResult;
 use typst::text::{Font, FontBook, FontInfo, FontVariant};
 use typst_timing::TimingScope;
 
-use crate::args::FontsCommand;
+use crate::args::{FontArgs, FontsCommand};
 
 /// Execute a font listing command.
 pub fn fonts(command: &FontsCommand) -> StrResult<()> {
     let mut searcher = FontSearcher::new();
-    searcher.search(&command.font_args.font_paths, command.font_args.ignore_system_fonts);
+    searcher.search_with(&command.font_args);
 
     for (name, infos) in searcher.book.families() {
         println!("{name}");
@@ -65,6 +69,40 @@ impl FontSearcher {
         Self { book: FontBook::new(), fonts: vec![] }
     }
 
+    /// Search the fonts configured by the arguments, going through the font
+    /// cache if one is given.
+    pub fn search_with(&mut self, args: &FontArgs) {
+        let Some(path) = &args.font_cache else {
+            return self.search(&args.font_paths, args.ignore_system_fonts);
+        };
+
+        let _scope = TimingScope::new("load font cache", None);
+        if let Some(cache) = FontCache::load(path)
+            .filter(|cache| cache.is_fresh(&args.font_paths, args.ignore_system_fonts))
+        {
+            for font in cache.fonts {
+                self.book.push(font.info);
+                self.fonts.push(FontSlot {
+                    path: font.path,
+                    index: font.index,
+                    font: OnceLock::new(),
+                });
+            }
+
+            #[cfg(feature = "embed-fonts")]
+            self.add_embedded();
+            return;
+        }
+
+        self.search(&args.font_paths, args.ignore_system_fonts);
+
+        // Writing the cache is best effort. If it fails, the next run simply
+        // searches again.
+        FontCache::new(self, &args.font_paths, args.ignore_system_fonts)
+            .save(path)
+            .ok();
+    }
+
     /// Search everything that is available.
     pub fn search(&mut self, font_paths: &[PathBuf], ignore_system_fonts: bool) {
         let mut db = Database::new();
@@ -122,3 +160,242 @@ impl FontSearcher {
         }
     }
 }
+
+/// An index of discovered fonts that is kept on disk between runs.
+#[derive(Serialize, Deserialize)]
+struct FontCache {
+    /// The font directories that were searched explicitly.
+    font_paths: Vec<PathBuf>,
+    /// Whether system fonts were left out.
+    ignore_system_fonts: bool,
+    /// The searched directories and their subdirectories, with their
+    /// modification times when they were indexed, or `None` if they didn't
+    /// exist. If any of them changed, fonts may have been added or removed.
+    dirs: Vec<(PathBuf, Option<SystemTime>)>,
+    /// The indexed fonts, in priority order.
+    fonts: Vec<CachedFont>,
+}
+
+/// A font in the [`FontCache`].
+#[derive(Serialize, Deserialize)]
+struct CachedFont {
+    path: PathBuf,
+    index: u32,
+    info: FontInfo,
+}
+
+impl FontCache {
+    /// Create a cache from the fonts that were just searched. Embedded fonts
+    /// are left out, as they are always available.
+    fn new(
+        searcher: &FontSearcher,
+        font_paths: &[PathBuf],
+        ignore_system_fonts: bool,
+    ) -> Self {
+        let fonts: Vec<_> = searcher
+            .fonts
+            .iter()
+            .enumerate()
+            .filter(|(_, slot)| !slot.path.as_os_str().is_empty())
+            .filter_map(|(i, slot)| {
+                Some(CachedFont {
+                    path: slot.path.clone(),
+                    index: slot.index,
+                    info: searcher.book.info(i)?.clone(),
+                })
+            })
+            .collect();
+
+        let mut roots = font_paths.to_vec();
+        if !ignore_system_fonts {
+            roots.extend(system_font_dirs());
+        }
+
+        let mut dirs = BTreeSet::new();
+        for root in &roots {
+            collect_dirs(root, &mut dirs);
+        }
+
+        // Fontconfig may point to directories beyond the default ones, which
+        // are at least covered through the fonts found in them.
+        dirs.extend(fonts.iter().filter_map(|font| Some(font.path.parent()?.to_owned())));
+
+        let dirs = dirs
+            .into_iter()
+            .map(|dir| {
+                let time = modified(&dir);
+                (dir, time)
+            })
+            .collect();
+
+        Self {
+            font_paths: font_paths.to_vec(),
+            ignore_system_fonts,
+            dirs,
+            fonts,
+        }
+    }
+
+    /// Load the cache from a file, if it exists and is valid.
+    fn load(path: &Path) -> Option<Self> {
+        let data = fs::read(path).ok()?;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Write the cache to a file.
+    fn save(&self, path: &Path) -> StrResult<()> {
+        let data = serde_json::to_vec(self)
+            .map_err(|err| eco_format!("failed to serialize font cache ({err})"))?;
+        fs::write(path, data)
+            .map_err(|err| eco_format!("failed to write font cache ({err})"))
+    }
+
+    /// Whether the cache still reflects the fonts that a search with the
+    /// given arguments would find.
+    fn is_fresh(&self, font_paths: &[PathBuf], ignore_system_fonts: bool) -> bool {
+        self.font_paths == font_paths
+            && self.ignore_system_fonts == ignore_system_fonts
+            && self.dirs.iter().all(|(dir, time)| modified(dir) == *time)
+    }
+}
+
+/// The directories that are searched for system fonts, unless fontconfig
+/// configures others.
+fn system_font_dirs() -> Vec<PathBuf> {
+    let mut paths = vec![];
+
+    #[cfg(target_os = "windows")]
+    {
+        let windir = std::env::var_os("WINDIR").unwrap_or_else(|| "C:\\Windows".into());
+        paths.push(Path::new(&windir).join("Fonts"));
+        if let Some(local) = dirs::data_local_dir() {
+            paths.push(local.join("Microsoft").join("Windows").join("Fonts"));
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    {
+        paths.extend(
+            ["/Library/Fonts", "/System/Library/Fonts", "/Network/Library/Fonts"]
+                .map(PathBuf::from),
+        );
+        if let Some(home) = dirs::home_dir() {
+            paths.push(home.join("Library").join("Fonts"));
+        }
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    {
+        paths.extend(["/usr/share/fonts", "/usr/local/share/fonts"].map(PathBuf::from));
+        if let Some(home) = dirs::home_dir() {
+            paths.push(home.join(".fonts"));
+            paths.push(home.join(".local").join("share").join("fonts"));
+        }
+    }
+
+    paths
+}
+
+/// Add a directory and, as fonts are searched recursively, all directories
+/// below it.
+///
+/// The directory is added even if it doesn't exist, so that its creation is
+/// noticed.
+fn collect_dirs(dir: &Path, dirs: &mut BTreeSet<PathBuf>) {
+    if !dirs.insert(dir.to_owned()) {
+        return;
+    }
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|ty| ty.is_dir()) {
+            collect_dirs(&entry.path(), dirs);
+        }
+    }
+}
+
+/// The modification time of a directory, if it exists.
+fn modified(dir: &Path) -> Option<SystemTime> {
+    fs::metadata(dir).and_then(|meta| meta.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use clap::Parser;
+
+    use super::*;
+    use crate::args::QueryCommand;
+
+    #[test]
+    fn test_font_cache_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fonts.json");
+        let font_paths = [dir.path().to_owned()];
+
+        let cache = FontCache::new(&FontSearcher::new(), &font_paths, true);
+        cache.save(&path).unwrap();
+        let loaded = FontCache::load(&path).unwrap();
+        assert_eq!(loaded.font_paths, font_paths);
+        assert!(loaded.ignore_system_fonts);
+        assert_eq!(loaded.dirs, cache.dirs);
+        assert!(loaded.fonts.is_empty());
+
+        fs::write(&path, "{").unwrap();
+        assert!(FontCache::load(&path).is_none());
+    }
+
+    #[test]
+    fn test_font_cache_is_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        fs::create_dir(&sub).unwrap();
+        let font_paths = [dir.path().to_owned()];
+        let cache = || FontCache::new(&FontSearcher::new(), &font_paths, true);
+
+        let before = cache();
+        assert!(before.is_fresh(&font_paths, true));
+        assert!(!before.is_fresh(&font_paths, false));
+        assert!(!before.is_fresh(&[], true));
+
+        // Adding a font to a subdirectory is noticed.
+        fs::write(sub.join("font.ttf"), b"").unwrap();
+        assert!(!before.is_fresh(&font_paths, true));
+
+        // So is removing it again.
+        let after = cache();
+        assert!(after.is_fresh(&font_paths, true));
+        fs::remove_file(sub.join("font.ttf")).unwrap();
+        assert!(!after.is_fresh(&font_paths, true));
+    }
+
+    #[test]
+    fn test_query_font_args() {
+        // A font that is only available through `--font-path`.
+        let dir = tempfile::tempdir().unwrap();
+        let font = dir.path().join("font.otf");
+        fs::write(&font, typst_assets::fonts().next().unwrap()).unwrap();
+
+        let command = QueryCommand::try_parse_from([
+            OsStr::new("query"),
+            OsStr::new("--font-path"),
+            dir.path().as_os_str(),
+            OsStr::new("--ignore-system-fonts"),
+            OsStr::new("main.typ"),
+            OsStr::new("heading"),
+        ])
+        .unwrap();
+
+        // The query world searches fonts with these arguments, just like the
+        // one of `typst compile`. Embedded fonts have no path.
+        let mut searcher = FontSearcher::new();
+        searcher.search_with(&command.common.font_args);
+        let paths: Vec<_> = searcher
+            .fonts
+            .iter()
+            .map(|slot| &slot.path)
+            .filter(|path| !path.as_os_str().is_empty())
+            .collect();
+        assert_eq!(paths, [&font]);
+    }
+}
@@ -66,6 +66,11 @@ fn set_failed() {
     EXIT.with(|cell| cell.set(ExitCode::FAILURE));
 }
 
+/// Set a specific exit code, e.g. as requested by `typst query --if-found`.
+fn set_exit_code(code: u8) {
+    EXIT.with(|cell| cell.set(ExitCode::from(code)));
+}
+
 /// Used by `args.rs`.
 fn typst_version() -> &'static str {
     env!("TYPST_VERSION")
@@ -21,6 +21,8 @@ const DEFAULT_PACKAGES_SUBDIR: &str = "typst/packages";
 pub struct PackageStorage {
     pub package_cache_path: Option<PathBuf>,
     pub package_path: Option<PathBuf>,
+    /// Whether packages must not be downloaded.
+    pub offline: bool,
 }
 
 impl PackageStorage {
@@ -31,7 +33,11 @@ impl PackageStorage {
         let package_path = args.package_path.clone().or_else(|| {
             dirs::data_dir().map(|data_dir| data_dir.join(DEFAULT_PACKAGES_SUBDIR))
         });
-        Self { package_cache_path, package_path }
+        Self {
+            package_cache_path,
+            package_path,
+            offline: args.offline,
+        }
     }
 
     /// Make a package available in the on-disk cache.
@@ -53,6 +59,12 @@ impl PackageStorage {
 
             // Download from network if it doesn't exist yet.
             if spec.namespace == "preview" {
+                if self.offline {
+                    return Err(PackageError::Other(Some(eco_format!(
+                        "package {spec} is not cached and downloading is \
+                         disabled with `--offline`"
+                    ))));
+                }
                 download_package(spec, &dir)?;
                 if dir.exists() {
                     return Ok(dir);
@@ -69,6 +81,13 @@ impl PackageStorage {
         spec: &VersionlessPackageSpec,
     ) -> StrResult<PackageVersion> {
         if spec.namespace == "preview" {
+            if self.offline {
+                bail!(
+                    "cannot determine the latest version of {spec} offline \
+                     (specify the desired version instead)"
+                );
+            }
+
             // For `@preview`, download the package index and find the latest
             // version.
             download_index()?
@@ -110,7 +129,11 @@ fn download_package(spec: &PackageSpec, package_dir: &Path) -> PackageResult<()>
         Err(ureq::Error::Status(404, _)) => {
             return Err(PackageError::NotFound(spec.clone()))
         }
-        Err(err) => return Err(PackageError::NetworkFailed(Some(eco_format!("{err}")))),
+        Err(err) => {
+            return Err(PackageError::NetworkFailed(Some(eco_format!(
+                "could not fetch {spec} from {HOST}: {err}"
+            ))))
+        }
     };
 
     let decompressed = flate2::read::GzDecoder::new(data.as_slice());
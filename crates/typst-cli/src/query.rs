@@ -1,51 +1,1049 @@
-use comemo::Track;
-use ecow::{eco_format, EcoString};
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::hash::Hasher;
+use std::io::{self, IsTerminal, Write};
+use std::iter::{self, Peekable};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use clap::{CommandFactory, ValueEnum};
+use comemo::{Track, Tracked};
+use ecow::{eco_format, EcoString, EcoVec};
 use serde::Serialize;
-use typst::diag::{bail, HintedStrResult, StrResult, Warned};
-use typst::eval::{eval_string, EvalMode};
-use typst::foundations::{Content, IntoValue, LocatableSelector, Scope};
-use typst::model::Document;
-use typst::syntax::Span;
+use siphasher::sip128::{Hasher128, SipHasher13};
+use typst::diag::{bail, warning, HintedStrResult, SourceDiagnostic, StrResult, Warned};
+use typst::engine::{Engine, Route, Sink, Traced};
+use typst::foundations::{
+    dict, Array, CastInfo, Content, Dict, Element, IntoValue, Label, LocatableSelector,
+    NativeElement, NoneValue, Repr, Scope, Selector, SequenceElem, Smart, StyleChain,
+    StyledElem, Styles, Type, Value,
+};
+use typst::introspection::{Introspector, Locatable, Location, MetadataElem};
+use typst::layout::{
+    Abs, Em, Frame, HideElem, Length, Margin, PageElem, PageRanges, Rel,
+};
+use typst::model::{BibliographyElem, Document, FootnoteElem, HeadingElem, ParbreakElem};
+use typst::syntax::{is_id_continue, is_ident, Span};
+use typst::text::{LinebreakElem, RawElem};
+use typst::visualize::Color;
 use typst::World;
+use typst_ide::QuerySession;
 
-use crate::args::{QueryCommand, SerializationFormat};
-use crate::compile::print_diagnostics;
-use crate::set_failed;
+use crate::args::{
+    DataUriFormat, Input, NumberMode, Output, PageRangeArgument, QueryCommand, RenderFit,
+    SelectorDialect, SerializationFormat,
+};
+use crate::compile::{
+    convert_datetime, hint_invalid_main_file, json_diagnostics, print_diagnostics,
+};
+use crate::watch::Status;
 use crate::world::SystemWorld;
+use crate::{print_error, set_exit_code, set_failed};
+
+impl QueryCommand {
+    /// The output path for rendered elements.
+    ///
+    /// With `--output-dir`, this still contains the placeholders of
+    /// `--name-template`.
+    pub fn render_output(&self) -> StrResult<Output> {
+        if let Some(dir) = &self.output_dir {
+            return Ok(Output::Path(dir.join(&self.name_template)));
+        }
+
+        if let Some(output) = &self.output {
+            return Ok(output.clone());
+        }
+
+        let Input::Path(path) = &self.common.input else {
+            bail!("output must be specified when rendering input from stdin");
+        };
+
+        Ok(Output::Path(path.with_extension("svg")))
+    }
+
+    /// The format to render elements in, inferred from the output path.
+    pub fn render_format(&self) -> RenderFormat {
+        match self.data_uri {
+            Some(DataUriFormat::Svg) => return RenderFormat::Svg,
+            Some(DataUriFormat::Png) => return RenderFormat::Png,
+            None => {}
+        }
+
+        let path = match (&self.output_dir, &self.output) {
+            (Some(_), _) => Path::new(&self.name_template),
+            (None, Some(Output::Path(path))) => path.as_path(),
+            (None, _) => return RenderFormat::Svg,
+        };
+
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => RenderFormat::Png,
+            Some(ext) if ext.eq_ignore_ascii_case("pdf") => RenderFormat::Pdf,
+            _ => RenderFormat::Svg,
+        }
+    }
+
+    /// The background color of rendered elements, if any.
+    pub fn background(&self) -> StrResult<Option<Color>> {
+        let color = match self.background.to_lowercase().as_str() {
+            "none" => return Ok(None),
+            "black" => Color::BLACK,
+            "gray" => Color::GRAY,
+            "white" => Color::WHITE,
+            "silver" => Color::SILVER,
+            "navy" => Color::NAVY,
+            "blue" => Color::BLUE,
+            "aqua" => Color::AQUA,
+            "teal" => Color::TEAL,
+            "eastern" => Color::EASTERN,
+            "purple" => Color::PURPLE,
+            "fuchsia" => Color::FUCHSIA,
+            "maroon" => Color::MAROON,
+            "red" => Color::RED,
+            "orange" => Color::ORANGE,
+            "yellow" => Color::YELLOW,
+            "olive" => Color::OLIVE,
+            "green" => Color::GREEN,
+            "lime" => Color::LIME,
+            hex => Color::from_str(hex).map_err(|err| {
+                eco_format!("invalid background color `{}` ({err})", self.background)
+            })?,
+        };
+        Ok(Some(color))
+    }
+
+    /// The selector, given either inline or in a file.
+    pub fn selector(&self) -> StrResult<String> {
+        match (&self.selector, &self.selector_file) {
+            (Some(selector), None) => Ok(selector.clone()),
+            (None, Some(path)) => fs::read_to_string(path).map_err(|err| {
+                eco_format!("failed to read selector file {} ({err})", path.display())
+            }),
+            (Some(_), Some(_)) => {
+                bail!("a selector cannot be given both inline and with `--selector-file`")
+            }
+            (None, None) => bail!("no selector was given"),
+        }
+    }
+
+    /// The input as a glob pattern, if it is one.
+    ///
+    /// Paths of existing files are never treated as patterns, even if they
+    /// contain special characters.
+    pub fn input_pattern(&self) -> Option<&str> {
+        let Input::Path(path) = &self.common.input else { return None };
+        let pattern = path.to_str()?;
+        (pattern.contains(['*', '?', '[']) && !path.exists()).then_some(pattern)
+    }
+
+    /// How much whitespace to serialize text formats with.
+    pub fn layout(&self) -> Layout {
+        if self.pretty {
+            Layout::Pretty
+        } else if self.compact {
+            Layout::Compact
+        } else {
+            Layout::Default
+        }
+    }
+
+    /// The number of characters to cut the lines of `--summary` to, if any.
+    pub fn summary_width(&self) -> Option<usize> {
+        let terminal = self.output.is_none()
+            && self.baseline.is_none()
+            && io::stdout().is_terminal();
+        self.summary_width.or(terminal.then_some(80))
+    }
+
+    /// The ranges of the pages to retrieve elements from.
+    ///
+    /// This returns `None` if elements on all pages should be retrieved.
+    pub fn page_ranges(&self) -> Option<PageRanges> {
+        self.pages.as_ref().map(|ranges| {
+            PageRanges::new(ranges.iter().map(PageRangeArgument::to_range).collect())
+        })
+    }
+}
 
 /// Execute a query command.
 pub fn query(command: &QueryCommand) -> HintedStrResult<()> {
+    if command.capabilities {
+        println!("{}", capabilities());
+        return Ok(());
+    }
+
+    if let Some(pattern) = command.input_pattern() {
+        return query_glob(pattern, command);
+    }
+
+    if command.watch {
+        return crate::watch::watch_query(command);
+    }
+
     let mut world = SystemWorld::new(&command.common)?;
 
+    // Evaluate the selectors up front, so that a malformed one doesn't waste
+    // a full compilation.
+    let selectors = eval_selectors(&world, command)?;
+    if !command.also.is_empty() {
+        let Selectors::Single(selector) = &selectors else {
+            bail!("`--also` requires a single selector");
+        };
+        return query_merged(selector, command);
+    }
+
+    if let Some(document) = compile_once(&mut world, command)? {
+        query_once(&world, &document, &selectors, command)?;
+    }
+
+    Ok(())
+}
+
+/// Create a query session for the command's input. The document is compiled
+/// lazily, with the first query.
+pub fn open_session(
+    command: &QueryCommand,
+) -> HintedStrResult<QuerySession<SystemWorld>> {
+    Ok(QuerySession::new(SystemWorld::new(&command.common)?))
+}
+
+/// Recompile the session's document if it is outdated, printing any
+/// diagnostics, and return it.
+///
+/// Returns `None` if the document could not be compiled.
+pub fn refresh_session<'a>(
+    session: &'a mut QuerySession<SystemWorld>,
+    command: &QueryCommand,
+) -> HintedStrResult<Option<&'a Document>> {
+    session.refresh_with(|world| compile_once(world, command))
+}
+
+/// Run a query against the session's document, recompiling it first if
+/// needed.
+///
+/// The phase timings aren't reset, so that a compilation in an earlier
+/// `refresh_session` is still reported by `--envelope`. Reset them with
+/// `reset_phases` before refreshing instead.
+pub fn query_session(
+    session: &mut QuerySession<SystemWorld>,
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    let selectors = eval_selectors(session.world(), command)?;
+    refresh_session(session, command)?;
+    if let Some(document) = session.document() {
+        query_once(session.world(), document, &selectors, command)?;
+    }
+    Ok(())
+}
+
+/// Run a query against the session's document and write its results as an
+/// event, as requested by `--events`.
+///
+/// If the document fails to compile, an error event was already written
+/// instead.
+pub fn emit_session(
+    session: &mut QuerySession<SystemWorld>,
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    reset_phases();
+    let Selectors::Single(selector) = eval_selectors(session.world(), command)? else {
+        bail!("streaming events requires a single selector");
+    };
+    refresh_session(session, command)?;
+    let Some(document) = session.document() else { return Ok(()) };
+
+    let world = session.world();
+    let results: Array = retrieve(world, command, document, &selector)
+        .into_iter()
+        .filter_map(|elem| try_map_element(world, document, elem, command))
+        .collect();
+    write_event(
+        "results",
+        dict! {
+            "count" => results.len(),
+            "results" => apply_number_mode(results.into_value(), command),
+        },
+    )
+}
+
+/// Write a line of the event stream requested by `--events` to stdout.
+///
+/// The event's kind and the current time come first, followed by the data.
+pub fn write_event(kind: &str, data: Dict) -> HintedStrResult<()> {
+    let mut event = dict! {
+        "event" => kind,
+        "timestamp" => chrono::Utc::now().to_rfc3339(),
+    };
+    event.extend(data);
+
+    let line = serde_json::to_string(&event)
+        .map_err(|err| eco_format!("failed to serialize event ({err})"))?;
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{line}")
+        .and_then(|_| stdout.flush())
+        .map_err(|err| eco_format!("failed to write event ({err})"))?;
+    Ok(())
+}
+
+/// Describe what the query command supports, as requested with
+/// `--capabilities`.
+fn capabilities() -> serde_json::Value {
+    let formats: Vec<_> = SerializationFormat::value_variants()
+        .iter()
+        .filter_map(ValueEnum::to_possible_value)
+        .map(|value| value.get_name().to_string())
+        .collect();
+    let options: Vec<_> = QueryCommand::command()
+        .get_arguments()
+        .filter_map(|arg| arg.get_long())
+        .map(|long| format!("--{long}"))
+        .collect();
+
+    serde_json::json!({
+        "version": crate::typst_version(),
+        "formats": formats,
+        "render-formats": ["svg", "png", "pdf"],
+        "options": options,
+    })
+}
+
+/// Query the main file and the files given with `--also`, each compiled on
+/// its own, and write out the merged results.
+///
+/// Each result is tagged with the input file it stems from.
+fn query_merged(
+    selector: &LocatableSelector,
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    if command.format == SerializationFormat::Toml {
+        bail!(
+            "cannot serialize multiple elements as TOML, \
+             which requires a table at the top level"
+        );
+    }
+
+    let inputs = iter::once(command.common.input.clone())
+        .chain(command.also.iter().cloned().map(Input::Path));
+
+    let mut values = Array::new();
+    for input in inputs {
+        let mut command = command.clone();
+        command.common.input = input;
+
+        let mut world = SystemWorld::new(&command.common)?;
+        let Some(document) = compile_once(&mut world, &command)? else { continue };
+        let name = codespan_reporting::files::Files::name(&world, world.main())
+            .unwrap_or_default();
+
+        let is_field = maps_to_field(&command);
+        for elem in retrieve(&world, &command, &document, selector) {
+            if let Some(value) = try_map_element(&world, &document, elem, &command) {
+                values.push(attach(value, "input", name.as_str().into_value(), is_field));
+            }
+        }
+    }
+
+    set_match_exit_code(command, !values.is_empty());
+    write(&apply_number_mode(values.into_value(), command), command)
+}
+
+/// Query every file that matches the glob pattern, each compiled on its own,
+/// and write out the results grouped by file path.
+///
+/// A file that fails to compile is reported and fails the command, but
+/// doesn't stop the other files from being queried.
+fn query_glob(pattern: &str, command: &QueryCommand) -> HintedStrResult<()> {
+    if let Some(option) = glob_conflict(command) {
+        bail!("a glob pattern as input cannot be combined with `{option}`");
+    }
+
+    let mut paths = glob::glob(pattern)
+        .map_err(|err| eco_format!("invalid glob pattern `{pattern}` ({err})"))?
+        .filter_map(Result::ok)
+        .filter(|path| path.is_file())
+        .peekable();
+    if paths.peek().is_none() {
+        bail!("no files matched `{pattern}`");
+    }
+
+    let mut dict = Dict::new();
+    let mut found = false;
+    for path in paths {
+        let mut command = command.clone();
+        command.common.input = Input::Path(path.clone());
+
+        let mut world = match SystemWorld::new(&command.common) {
+            Ok(world) => world,
+            Err(err) => {
+                set_failed();
+                print_error(&format!("failed to query {} ({err})", path.display()))
+                    .map_err(|err| eco_format!("failed to print error ({err})"))?;
+                continue;
+            }
+        };
+
+        let selector = match eval_selectors(&world, &command)? {
+            Selectors::Single(selector) => selector,
+            Selectors::None => bail!(
+                "querying a glob pattern requires a selector (or `--selector-file`)"
+            ),
+            Selectors::Multiple(_) => {
+                bail!("querying a glob pattern requires a single selector")
+            }
+        };
+        let Some(document) = compile_once(&mut world, &command)? else { continue };
+
+        let values: Array = retrieve(&world, &command, &document, &selector)
+            .into_iter()
+            .filter_map(|elem| try_map_element(&world, &document, elem, &command))
+            .collect();
+        found |= !values.is_empty();
+        dict.insert((&*path.to_string_lossy()).into(), values.into_value());
+    }
+
+    set_match_exit_code(command, found);
+    write(&apply_number_mode(dict.into_value(), command), command)
+}
+
+/// The first option given that can't be combined with a glob pattern as
+/// input.
+///
+/// Matches of a glob pattern are always output grouped by file path, so these
+/// options, which shape the output of a single document or write one of their
+/// own, don't apply to them. Diagnostics of files that fail to compile only
+/// go to stderr, so they can't be written to the output either. Clap can't reject these combinations as whether
+/// the input is a pattern is only known once it turns out not to exist.
+fn glob_conflict(command: &QueryCommand) -> Option<&'static str> {
+    [
+        (command.watch, "--watch"),
+        (!command.also.is_empty(), "--also"),
+        (command.count, "--count"),
+        (command.one, "--one"),
+        (command.index.is_some(), "--index"),
+        (command.check, "--check"),
+        (command.render, "--render"),
+        (command.explain, "--explain"),
+        (command.tree, "--tree"),
+        (command.summary, "--summary"),
+        (command.schema, "--schema"),
+        (command.envelope, "--envelope"),
+        (command.key_by_label, "--key-by-label"),
+        (command.group_by.is_some(), "--group-by"),
+        (command.fail_on_multiple, "--fail-on-multiple"),
+        (command.max_matches.is_some(), "--max-matches"),
+        (command.stats, "--stats"),
+        (command.profile, "--profile"),
+        (command.errors_as_output, "--errors-as-output"),
+    ]
+    .into_iter()
+    .find_map(|(given, option)| given.then_some(option))
+}
+
+/// The evaluated selectors of a query command.
+pub enum Selectors {
+    /// No selector, as elements are only listed or the document is described.
+    None,
+    /// A single selector, whose matches are output on their own.
+    Single(LocatableSelector),
+    /// Selectors given with `--selector`, whose matches are output keyed by
+    /// the selector's source text.
+    Multiple(Vec<(String, LocatableSelector)>),
+}
+
+/// Evaluate the selectors of the command.
+pub fn eval_selectors(
+    world: &dyn World,
+    command: &QueryCommand,
+) -> HintedStrResult<Selectors> {
+    if command.list_selectors
+        || command.document_info
+        || command.text
+        || command.bibliography
+        || command.raw
+    {
+        return Ok(Selectors::None);
+    }
+
+    if let Some(id) = &command.location {
+        let location = parse_location_id(id)?;
+        return Ok(Selectors::Single(LocatableSelector(Selector::Location(location))));
+    }
+
+    let scope = eval_definitions(world, command)?;
+    if !command.selectors.is_empty() {
+        return command
+            .selectors
+            .iter()
+            .map(|text| {
+                let code = selector_code(text, command)?;
+                let selector = typst_ide::eval_selector(world, &code, scope.clone())?;
+                Ok((text.clone(), selector))
+            })
+            .collect::<HintedStrResult<_>>()
+            .map(Selectors::Multiple);
+    }
+
+    let selector = command.selector()?;
+    if selector.ends_with(".typ") && Path::new(&selector).is_file() {
+        bail!(
+            "`{selector}` is a file, not a selector \
+             (to query multiple files, pass them with `--also`)"
+        );
+    }
+
+    let code = selector_code(&selector, command)?;
+    typst_ide::eval_selector(world, &code, scope).map(Selectors::Single)
+}
+
+/// Turn a selector as given by the user into Typst code.
+fn selector_code(text: &str, command: &QueryCommand) -> StrResult<String> {
+    match command.selector_dialect {
+        SelectorDialect::Typst => Ok(text.into()),
+        SelectorDialect::Css => translate_css(text),
+    }
+}
+
+/// Translate a selector in the CSS-like syntax of `--selector-dialect css`
+/// into Typst code.
+///
+/// An element name can be followed by attribute filters (`heading[level=1]`)
+/// and classes, which filter figures by kind (`figure.image`). An id matches
+/// the label of the same name (`#intro`). Several selectors separated by
+/// commas match any of them. Attribute values are Typst code.
+fn translate_css(text: &str) -> StrResult<String> {
+    let mut chars = text.chars().peekable();
+    let mut alternatives = vec![];
+    loop {
+        skip_whitespace(&mut chars);
+        alternatives.push(translate_css_compound(&mut chars)?);
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => break,
+            Some(',') => continue,
+            Some(c) => bail!("unsupported character `{c}` in CSS selector"),
+        }
+    }
+
+    Ok(match alternatives.as_slice() {
+        [single] => single.clone(),
+        [first, rest @ ..] => {
+            let mut code = format!("selector({first})");
+            for alternative in rest {
+                code.push_str(&format!(".or({alternative})"));
+            }
+            code
+        }
+        [] => unreachable!(),
+    })
+}
+
+/// Translate a single CSS selector without commas, like `heading[level=1]`.
+fn translate_css_compound(
+    chars: &mut Peekable<impl Iterator<Item = char>>,
+) -> StrResult<String> {
+    let name = take_css_ident(chars);
+    let mut fields = vec![];
+    let mut label = None;
+    loop {
+        match chars.peek() {
+            Some('[') => {
+                chars.next();
+                skip_whitespace(chars);
+                let key = take_css_ident(chars);
+                skip_whitespace(chars);
+                if key.is_empty() || chars.next() != Some('=') {
+                    bail!(
+                        "expected an attribute filter like `[key=value]` in CSS selector"
+                    );
+                }
+                let value = take_css_value(chars)?;
+                fields.push(format!("{key}: {value}"));
+            }
+            Some('.') => {
+                chars.next();
+                let kind = take_css_ident(chars);
+                if kind.is_empty() {
+                    bail!("expected a figure kind after `.` in CSS selector");
+                }
+                fields.push(format!("kind: {kind}"));
+            }
+            Some('#') => {
+                chars.next();
+                let id: String = iter::from_fn(|| {
+                    chars
+                        .next_if(|&c| c.is_alphanumeric() || matches!(c, '-' | '_' | ':'))
+                })
+                .collect();
+                if id.is_empty() {
+                    bail!("expected a label after `#` in CSS selector");
+                }
+                label = Some(id);
+            }
+            _ => break,
+        }
+    }
+
+    if name.is_empty() {
+        return match label {
+            Some(label) if fields.is_empty() => Ok(format!("<{label}>")),
+            Some(_) => bail!("attribute filters and classes require an element name"),
+            None => bail!("expected an element name or `#label` in CSS selector"),
+        };
+    }
+
+    let mut code = name;
+    if !fields.is_empty() {
+        code.push_str(&format!(".where({})", fields.join(", ")));
+    }
+    if let Some(label) = label {
+        code = format!("selector({code}).and(<{label}>)");
+    }
+    Ok(code)
+}
+
+/// Take the value of an attribute filter up to and including the closing
+/// bracket. Quoted strings may contain brackets.
+fn take_css_value(chars: &mut impl Iterator<Item = char>) -> StrResult<String> {
+    let mut value = String::new();
+    let mut quoted = false;
+    loop {
+        match chars.next() {
+            Some(']') if !quoted => break,
+            Some('"') => {
+                quoted = !quoted;
+                value.push('"');
+            }
+            Some('\\') if quoted => {
+                value.push('\\');
+                value.extend(chars.next());
+            }
+            Some(c) => value.push(c),
+            None => bail!("unclosed attribute filter in CSS selector"),
+        }
+    }
+
+    let value = value.trim();
+    if value.is_empty() {
+        bail!("attribute filter is missing a value in CSS selector");
+    }
+    Ok(value.into())
+}
+
+/// Take an identifier, like an element or field name.
+fn take_css_ident(chars: &mut Peekable<impl Iterator<Item = char>>) -> String {
+    iter::from_fn(|| chars.next_if(|&c| is_id_continue(c))).collect()
+}
+
+/// Skip over any whitespace.
+fn skip_whitespace(chars: &mut Peekable<impl Iterator<Item = char>>) {
+    while chars.next_if(|c| c.is_whitespace()).is_some() {}
+}
+
+/// The id of a location, as output by `--with-location`.
+fn location_id(location: Location) -> EcoString {
+    eco_format!("{:032x}", location.hash())
+}
+
+/// Parse a location id given with `--location`.
+fn parse_location_id(id: &str) -> StrResult<Location> {
+    u128::from_str_radix(id, 16).map(Location::new).map_err(|_| {
+        eco_format!(
+            "invalid location id `{id}` (expected an `id` from `--with-location`)"
+        )
+    })
+}
+
+/// Evaluate the selectors defined with `--define` into a scope, in order.
+fn eval_definitions(world: &dyn World, command: &QueryCommand) -> HintedStrResult<Scope> {
+    let global = world.library().global.scope();
+    let mut scope = Scope::new();
+    for (name, text) in &command.definitions {
+        if !is_ident(name) {
+            bail!("cannot define `{name}`, as it is not a valid identifier");
+        }
+        if global.get(name).is_some() {
+            bail!(
+                "cannot define `{name}`, as it would shadow a built-in definition";
+                hint: "choose a different name"
+            );
+        }
+        if scope.get(name).is_some() {
+            bail!("`{name}` is defined more than once");
+        }
+
+        let selector = typst_ide::eval_selector(world, text, scope.clone())
+            .map_err(|err| eco_format!("failed to define `{name}` ({err})"))?;
+        scope.define(name.as_str(), selector.0);
+    }
+    Ok(scope)
+}
+
+/// Compile the document once, printing any diagnostics.
+///
+/// Returns `None` if the document could not be compiled.
+pub fn compile_once(
+    world: &mut SystemWorld,
+    command: &QueryCommand,
+) -> HintedStrResult<Option<Document>> {
     // Reset everything and ensure that the main file is present.
     world.reset();
-    world.source(world.main()).map_err(|err| err.to_string())?;
+    if let Err(errors) = world
+        .source(world.main())
+        .map_err(|err| hint_invalid_main_file(err, &command.common.input))
+    {
+        set_failed();
+        print_diagnostics(world, &errors, &[], command.common.diagnostic_format)
+            .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+        write_diagnostics(world, &errors, &[], command)?;
+        return Ok(None);
+    }
 
-    let Warned { output, warnings } = typst::compile(&world);
+    if command.progress {
+        Status::Compiling
+            .print_message()
+            .map_err(|err| eco_format!("failed to print progress ({err})"))?;
+    }
+
+    let start = Instant::now();
+    let Warned { output, warnings } =
+        timed(command, "compile", || typst::compile(&*world));
+
+    if command.progress {
+        let duration = start.elapsed();
+        let status = match &output {
+            Ok(_) if warnings.is_empty() => Status::Success(duration),
+            Ok(_) => Status::PartialSuccess(duration),
+            Err(_) => Status::Error,
+        };
+        status
+            .print_message()
+            .map_err(|err| eco_format!("failed to print progress ({err})"))?;
+    }
 
     match output {
-        // Retrieve and print query results.
+        // Print warnings first, so that they aren't lost if the query itself
+        // fails. They go to stderr and thus never mix with the data.
         Ok(document) => {
-            let data = retrieve(&world, command, &document)?;
-            let serialized = format(data, command)?;
-            println!("{serialized}");
-            print_diagnostics(&world, &[], &warnings, command.common.diagnostic_format)
+            print_diagnostics(world, &[], &warnings, command.common.diagnostic_format)
                 .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+
+            // The query still runs, but the command fails in the end.
+            if command.deny_warnings && !warnings.is_empty() {
+                set_failed();
+            }
+
+            Ok(Some(document))
         }
 
         // Print diagnostics.
         Err(errors) => {
             set_failed();
             print_diagnostics(
-                &world,
+                world,
                 &errors,
                 &warnings,
                 command.common.diagnostic_format,
             )
             .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+            write_diagnostics(world, &errors, &warnings, command)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Write the diagnostics of a failed compilation to the output, if requested
+/// with `--errors-as-output`.
+fn write_diagnostics(
+    world: &SystemWorld,
+    errors: &[SourceDiagnostic],
+    warnings: &[SourceDiagnostic],
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    if !command.errors_as_output && !command.events {
+        return Ok(());
+    }
+
+    let diagnostics = json_diagnostics(world, errors, warnings);
+    if command.events {
+        let message = "failed to compile document";
+        let diagnostics = to_ordered_value(&diagnostics)?;
+        return write_event(
+            "error",
+            dict! { "message" => message, "diagnostics" => diagnostics },
+        );
+    }
+
+    write(&diagnostics, command)
+}
+
+/// Run the query against a compiled document and write out its results.
+pub fn query_once(
+    world: &SystemWorld,
+    document: &Document,
+    selectors: &Selectors,
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    if command.stats {
+        print_stats(document);
+    }
+    if command.profile {
+        print_profile(document);
+    }
+
+    match selectors {
+        Selectors::None if command.document_info => {
+            write(&document_info(document), command)
         }
+        Selectors::None if command.text => {
+            write_output(command, document_text(world)?.as_bytes())
+        }
+        Selectors::None if command.bibliography => {
+            write(&bibliography_entries(world, document)?, command)
+        }
+        Selectors::None if command.raw => write(&raw_texts(world)?, command),
+        Selectors::None => write(&count_elements(command, document), command),
+        Selectors::Single(selector) => {
+            let mut data = timed(command, "retrieve", || {
+                retrieve(world, command, document, selector)
+            });
+            set_match_exit_code(command, !data.is_empty());
+            fail_on_multiple(command, None, data.len())?;
+            let count = data.len();
+            if !command.check && !command.count {
+                cap_matches(world, &mut data, command)?;
+            }
+            if command.check {
+                eprintln!("found {}", describe_matches(data.len()));
+            } else if command.count {
+                write_output(command, eco_format!("{}\n", data.len()).as_bytes())?;
+            } else if command.summary {
+                let width = command.summary_width();
+                write_output(command, summarize(document, &data, width).as_bytes())?;
+            } else if command.explain {
+                write_output(command, explain(world, document, &data).as_bytes())?;
+            } else if command.schema {
+                write(&schema(&data, command), command)?;
+            } else if command.render {
+                render(world, document, &data, command)?;
+            } else if command.tree {
+                let value = tree(world, document, data, command)?;
+                write(&apply_number_mode(value, command), command)?;
+            } else if command.key_by_label {
+                let value = key_by_label(world, document, with_labels(data), command)?;
+                write(&apply_number_mode(value, command), command)?;
+            } else if let Some(field) = &command.group_by {
+                let value = group_by_field(world, document, data, field, command);
+                write(&apply_number_mode(value, command), command)?;
+            } else {
+                let truncated = data.len() < count;
+                let mut value = format(world, document, data, command)?;
+                if command.envelope {
+                    value = envelope(value, count, truncated).into_value();
+                }
+                write(&apply_number_mode(value, command), command)?;
+            }
+            Ok(())
+        }
+        Selectors::Multiple(selectors) => {
+            let mut dict = Dict::new();
+            let mut found = false;
+            for (text, selector) in selectors {
+                let mut elements = timed(command, "retrieve", || {
+                    retrieve(world, command, document, selector)
+                });
+                found |= !elements.is_empty();
+                fail_on_multiple(command, Some(text), elements.len())?;
+                if command.check {
+                    eprintln!("{text}: found {}", describe_matches(elements.len()));
+                    continue;
+                }
+                cap_matches(world, &mut elements, command)?;
+
+                let values: Array = elements
+                    .into_iter()
+                    .filter_map(|elem| try_map_element(world, document, elem, command))
+                    .collect();
+                dict.insert(text.as_str().into(), values.into_value());
+            }
+            set_match_exit_code(command, found);
+            if command.check {
+                return Ok(());
+            }
+            write(&apply_number_mode(dict.into_value(), command), command)
+        }
+    }
+}
+
+/// Truncate the elements to the number given with `--max-matches`, warning if
+/// any were dropped.
+fn cap_matches(
+    world: &SystemWorld,
+    elements: &mut Vec<Content>,
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    let Some(max) = command.max_matches else { return Ok(()) };
+    if elements.len() <= max {
+        return Ok(());
+    }
+
+    let warning = warning!(
+        Span::detached(),
+        "selector matched {} elements, but the output is capped at {max}",
+        elements.len();
+        hint: "raise the cap with `--max-matches` or narrow down the selector"
+    );
+    print_diagnostics(world, &[], &[warning], command.common.diagnostic_format)
+        .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+    elements.truncate(max);
+    Ok(())
+}
+
+/// Nest the retrieved headings by their level, as requested by `--tree`.
+fn tree(
+    world: &SystemWorld,
+    document: &Document,
+    elements: Vec<Content>,
+    command: &QueryCommand,
+) -> StrResult<Value> {
+    let mut nodes = vec![];
+    for elem in elements {
+        let Some(heading) = elem.to_packed::<HeadingElem>() else {
+            bail!(
+                "cannot build a tree from {} elements, only from headings",
+                elem.func().name()
+            );
+        };
+        let level = heading.resolve_level(StyleChain::default()).get();
+
+        if let Some(value) = try_map_element(world, document, elem, command) {
+            nodes.push((level, value));
+        }
+    }
+
+    let is_field = maps_to_field(command);
+    Ok(nest(&mut nodes.into_iter().peekable(), 0, is_field).into_value())
+}
+
+/// Take the headings below a parent of the given level, each with its own
+/// children, until one at the parent's level or above follows.
+fn nest(
+    nodes: &mut Peekable<impl Iterator<Item = (usize, Value)>>,
+    parent: usize,
+    is_field: bool,
+) -> Array {
+    let mut children = Array::new();
+    while let Some((level, value)) = nodes.next_if(|(level, _)| *level > parent) {
+        let grandchildren = nest(nodes, level, is_field);
+        children.push(attach(value, "children", grandchildren.into_value(), is_field));
+    }
+    children
+}
+
+/// Describe a number of matches, e.g. `1 match` or `3 matches`.
+fn describe_matches(n: usize) -> String {
+    format!("{n} {}", if n == 1 { "match" } else { "matches" })
+}
+
+/// Print statistics about the queryable elements of the document to stderr.
+fn print_stats(document: &Document) {
+    let introspector = &document.introspector;
+    let labels: HashSet<_> = introspector.all().filter_map(Content::label).collect();
+    eprintln!("elements: {}", introspector.all().count());
+    eprintln!("labels: {}", labels.len());
+    eprintln!("pages: {}", introspector.pages());
+}
+
+/// Run `f`, printing how long it took to stderr if `--timings` is given.
+///
+/// The timings go to stderr, so that they never mix with the data. They are
+/// also recorded for `--envelope`.
+fn timed<T>(command: &QueryCommand, phase: &'static str, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let output = f();
+    let elapsed = start.elapsed();
+    if command.timings {
+        eprintln!("{phase}: {elapsed:.2?}");
+    }
+
+    PHASES.with(|phases| {
+        let mut phases = phases.borrow_mut();
+        phases.retain(|&(other, _)| other != phase);
+        phases.push((phase, elapsed));
+    });
+    output
+}
+
+thread_local! {
+    /// How long the last run of each phase measured by `timed` took.
+    static PHASES: RefCell<Vec<(&'static str, Duration)>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// Forget the phases measured so far, so that a query only reports the
+/// phases that ran for it, e.g. no compilation if the document was unchanged.
+pub fn reset_phases() {
+    PHASES.with(|phases| phases.borrow_mut().clear());
+}
+
+/// How long the last run of a phase took in milliseconds, if it ran.
+fn phase_millis(phase: &str) -> Option<f64> {
+    PHASES.with(|phases| {
+        phases
+            .borrow()
+            .iter()
+            .find(|&&(other, _)| other == phase)
+            .map(|(_, elapsed)| elapsed.as_secs_f64() * 1000.0)
+    })
+}
+
+/// Wrap results together with their count and how long compilation and
+/// retrieval took, as requested by `--envelope`.
+///
+/// The count is that of all matches, also if the results were truncated with
+/// `--max-matches`.
+fn envelope(matches: Value, count: usize, truncated: bool) -> Dict {
+    dict! {
+        "matches" => matches,
+        "meta" => dict! {
+            "count" => count,
+            "truncated" => truncated,
+            "compile_ms" => phase_millis("compile"),
+            "retrieve_ms" => phase_millis("retrieve"),
+        },
+    }
+}
+
+/// Set the exit code requested with `--if-empty` or `--if-found`.
+fn set_match_exit_code(command: &QueryCommand, found: bool) {
+    let code = if found { command.if_found } else { command.if_empty };
+    if code != 0 {
+        set_exit_code(code);
+    }
+}
+
+/// Report an error and fail the command if more than one element was
+/// retrieved and `--fail-on-multiple` is set.
+///
+/// The query continues regardless, so that all matches are still output.
+fn fail_on_multiple(
+    command: &QueryCommand,
+    selector: Option<&str>,
+    n: usize,
+) -> HintedStrResult<()> {
+    if !command.fail_on_multiple || n <= 1 {
+        return Ok(());
     }
 
+    let message = match selector {
+        Some(text) => eco_format!("expected at most one element for `{text}`, found {n}"),
+        None => eco_format!("expected at most one element, found {n}"),
+    };
+    set_failed();
+    print_error(&message).map_err(|err| eco_format!("failed to print error ({err})"))?;
     Ok(())
 }
 
@@ -54,71 +1052,2355 @@ fn retrieve(
     world: &dyn World,
     command: &QueryCommand,
     document: &Document,
-) -> HintedStrResult<Vec<Content>> {
-    let selector = eval_string(
-        world.track(),
-        &command.selector,
-        Span::detached(),
-        EvalMode::Code,
-        Scope::default(),
-    )
-    .map_err(|errors| {
-        let mut message = EcoString::from("failed to evaluate selector");
-        for (i, error) in errors.into_iter().enumerate() {
-            message.push_str(if i == 0 { ": " } else { ", " });
-            message.push_str(&error.message);
-        }
-        message
-    })?
-    .cast::<LocatableSelector>()?;
-
-    Ok(document
-        .introspector
-        .query(&selector.0)
+    selector: &LocatableSelector,
+) -> Vec<Content> {
+    // The selector is already evaluated, so querying can't fail.
+    let ranges = command.page_ranges();
+    let mut elements: Vec<_> = typst_ide::query_document(world, document, selector)
+        .unwrap_or_default()
         .into_iter()
-        .collect::<Vec<_>>())
-}
+        .filter(|elem| on_pages(elem, ranges.as_ref(), document))
+        .collect();
 
-/// Format the query result in the output format.
-fn format(elements: Vec<Content>, command: &QueryCommand) -> StrResult<String> {
-    if command.one && elements.len() != 1 {
-        bail!("expected exactly one element, found {}", elements.len());
+    if command.exclude_hidden {
+        // The document already compiled, so evaluation can't fail here.
+        let hidden = hidden_locations(world.track(), document.introspector.track())
+            .unwrap_or_default();
+        elements
+            .retain(|elem| elem.location().map_or(true, |loc| !hidden.contains(&loc)));
     }
 
-    let mapped: Vec<_> = elements
-        .into_iter()
-        .filter_map(|c| match &command.field {
-            Some(field) => c.get_by_name(field),
-            _ => Some(c.into_value()),
-        })
-        .collect();
+    if command.min_depth.is_some() || command.max_depth.is_some() {
+        let min = command.min_depth.unwrap_or(0);
+        let max = command.max_depth.unwrap_or(usize::MAX);
+        let depths = nesting_depths(&document.introspector, &elements);
+        let mut depths = depths.into_iter();
+        elements
+            .retain(|_| depths.next().is_some_and(|depth| (min..=max).contains(&depth)));
+    }
 
-    if command.one {
-        let Some(value) = mapped.first() else {
-            bail!("no such field found for element");
-        };
-        serialize(value, command.format, command.pretty)
-    } else {
-        serialize(&mapped, command.format, command.pretty)
+    if command.unique {
+        dedup(&mut elements);
+    }
+
+    if let Some(field) = &command.sort_by {
+        sort_by_field(&mut elements, field, &document.introspector, command.reverse);
+    }
+
+    elements
+}
+
+/// Determine how many other elements each element is nested in.
+///
+/// The introspector doesn't record the element tree, so an element counts as
+/// nested in another one if the other one's content contains an element of
+/// the same kind that was created at the same place in the source. Elements
+/// without a source position thus always count as top-level.
+fn nesting_depths(introspector: &Introspector, elements: &[Content]) -> Vec<usize> {
+    let funcs: HashSet<Element> = elements.iter().map(Content::func).collect();
+    let selector =
+        Selector::Or(funcs.into_iter().map(|func| Selector::Elem(func, None)).collect());
+
+    let mut depths: HashMap<(Element, Span), usize> = HashMap::new();
+    for container in introspector.all() {
+        // The query also yields the container itself, which is the only
+        // result with a location.
+        let nested: HashSet<_> = container
+            .query(selector.clone())
+            .into_iter()
+            .filter(|elem| elem.location() != container.location())
+            .filter(|elem| !elem.span().is_detached())
+            .map(|elem| (elem.func(), elem.span()))
+            .collect();
+        for key in nested {
+            *depths.entry(key).or_default() += 1;
+        }
+    }
+
+    elements
+        .iter()
+        .map(|elem| depths.get(&(elem.func(), elem.span())).copied().unwrap_or(0))
+        .collect()
+}
+
+/// Pair each retrieved element with its label, if any.
+fn with_labels(elements: Vec<Content>) -> Vec<(Option<Label>, Content)> {
+    elements.into_iter().map(|elem| (elem.label(), elem)).collect()
+}
+
+/// Key the retrieved elements by their labels, as requested with
+/// `--key-by-label`.
+fn key_by_label(
+    world: &SystemWorld,
+    document: &Document,
+    elements: Vec<(Option<Label>, Content)>,
+    command: &QueryCommand,
+) -> StrResult<Value> {
+    let mut dict = Dict::new();
+    for (label, elem) in elements {
+        let Some(label) = label else { continue };
+        if dict.contains(label.as_str()) {
+            bail!(
+                "label `<{}>` is attached to multiple elements, \
+                 so they cannot be keyed by it",
+                label.as_str()
+            );
+        }
+
+        if let Some(value) = try_map_element(world, document, elem, command) {
+            dict.insert(label.as_str().into(), value);
+        }
+    }
+    Ok(dict.into_value())
+}
+
+/// Remove elements that serialize to the same value as an earlier one.
+///
+/// This collapses, for instance, the copies of a running header that is
+/// repeated on every page.
+fn dedup(elements: &mut Vec<Content>) {
+    let mut seen = HashSet::new();
+    elements.retain(|elem| match serde_json::to_vec(elem) {
+        Ok(serialized) => seen.insert(serialized),
+        Err(_) => true,
+    });
+}
+
+/// Sort elements by the value of a field, keeping those without it last.
+fn sort_by_field(
+    elements: &mut Vec<Content>,
+    field: &str,
+    introspector: &Introspector,
+    reverse: bool,
+) {
+    let mut keyed: Vec<_> = elements
+        .drain(..)
+        .map(|elem| (resolve_field(elem.clone(), field, introspector).ok(), elem))
+        .collect();
+
+    keyed.sort_by(|(a, _), (b, _)| match (a, b) {
+        (Some(a), Some(b)) if reverse => compare_values(b, a),
+        (Some(a), Some(b)) => compare_values(a, b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    });
+
+    elements.extend(keyed.into_iter().map(|(_, elem)| elem));
+}
+
+/// Group elements by the value of a field, as requested with `--group-by`.
+fn group_by_field(
+    world: &SystemWorld,
+    document: &Document,
+    elements: Vec<Content>,
+    field: &str,
+    command: &QueryCommand,
+) -> Value {
+    let mut groups = Dict::new();
+    for elem in elements {
+        let key = resolve_field(elem.clone(), field, &document.introspector).ok();
+        let key = group_key(key.as_ref());
+
+        let Some(value) = try_map_element(world, document, elem, command) else {
+            continue;
+        };
+        if !groups.contains(&key) {
+            groups.insert(key.as_str().into(), Array::new().into_value());
+        }
+        if let Ok(Value::Array(group)) = groups.at_mut(&key) {
+            group.push(value);
+        }
+    }
+    groups.into_value()
+}
+
+/// The key of the group for a field value.
+///
+/// Strings are used as is, while content is keyed by its text and functions,
+/// like the kind of a figure, by their name. A missing value is keyed as
+/// `null`.
+fn group_key(value: Option<&Value>) -> EcoString {
+    match value {
+        None | Some(Value::None) => "null".into(),
+        Some(Value::Str(s)) => s.as_str().into(),
+        Some(Value::Content(content)) => content.plain_text(),
+        Some(Value::Func(func)) => {
+            func.name().map(Into::into).unwrap_or_else(|| func.repr())
+        }
+        Some(Value::Bool(b)) => eco_format!("{b}"),
+        Some(Value::Int(n)) => eco_format!("{n}"),
+        Some(Value::Float(n)) => eco_format!("{n}"),
+        Some(value) => value.repr(),
+    }
+}
+
+/// Compare two field values for sorting.
+///
+/// Numbers compare numerically, while strings and content compare by their
+/// text. Other values are compared by their code representation.
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a.cmp(b),
+        (Value::Int(a), Value::Float(b)) => (*a as f64).total_cmp(b),
+        (Value::Float(a), Value::Int(b)) => a.total_cmp(&(*b as f64)),
+        (Value::Float(a), Value::Float(b)) => a.total_cmp(b),
+        (Value::Str(a), Value::Str(b)) => a.cmp(b),
+        (Value::Content(a), Value::Content(b)) => a.plain_text().cmp(&b.plain_text()),
+        _ => a.repr().cmp(&b.repr()),
+    }
+}
+
+/// Extract the plain text of the whole document, with a blank line between
+/// paragraphs and around headings.
+///
+/// Like `--field text` for single elements, this works on the document's
+/// content before show rules are applied, as the laid out document doesn't
+/// retain it.
+fn document_text(world: &dyn World) -> StrResult<EcoString> {
+    let mut paragraphs = vec![EcoString::new()];
+    collect_paragraphs(&eval_main(world.track())?, &mut paragraphs, &mut vec![]);
+
+    let mut text = EcoString::new();
+    for paragraph in paragraphs.iter().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        if !text.is_empty() {
+            text.push_str("\n\n");
+        }
+        text.push_str(paragraph);
+    }
+    text.push('\n');
+    Ok(text)
+}
+
+/// Find the text of the paragraph a footnote is referenced from, as requested
+/// by `--with-ref`.
+fn footnote_reference(
+    world: &dyn World,
+    introspector: &Introspector,
+    elem: &Content,
+) -> StrResult<Value> {
+    let Some(location) = elem.location().filter(|_| elem.is::<FootnoteElem>()) else {
+        return Ok(Value::None);
+    };
+    let references = footnote_references(world.track(), introspector.track())?;
+    Ok(references
+        .get(&location)
+        .map(|text| text.as_str().into_value())
+        .unwrap_or_default())
+}
+
+/// Find the paragraphs of all footnotes in the document, by their locations.
+///
+/// The paragraphs are taken from the evaluated, unlaid-out document, which
+/// lists the footnotes in the same order as the laid-out one. Footnotes
+/// created by the same code, like in a wrapper function, share a span, so the
+/// n-th laid-out footnote with a span is matched up with the n-th evaluated
+/// one. This is memoized, so the document is only walked once, however many
+/// footnotes are queried.
+#[comemo::memoize]
+fn footnote_references(
+    world: Tracked<dyn World + '_>,
+    introspector: Tracked<Introspector>,
+) -> StrResult<Arc<HashMap<Location, EcoString>>> {
+    let mut paragraphs = vec![EcoString::new()];
+    let mut anchors = vec![];
+    collect_paragraphs(&eval_main(world)?, &mut paragraphs, &mut anchors);
+
+    let mut occurrences = HashMap::<Span, Vec<usize>>::new();
+    for (span, i) in anchors {
+        occurrences.entry(span).or_default().push(i);
+    }
+
+    let mut seen = HashMap::<Span, usize>::new();
+    let mut references = HashMap::new();
+    for footnote in introspector.query(&FootnoteElem::elem().select()) {
+        let n = seen.entry(footnote.span()).or_default();
+        let paragraph = occurrences.get(&footnote.span()).and_then(|p| p.get(*n));
+        *n += 1;
+        if let (Some(&i), Some(location)) = (paragraph, footnote.location()) {
+            references.insert(location, paragraphs[i].trim().into());
+        }
+    }
+    Ok(Arc::new(references))
+}
+
+/// Evaluate the main file into its content without laying it out.
+///
+/// Evaluation is memoized, so this is cheap to repeat for the same sources.
+fn eval_main(world: Tracked<dyn World + '_>) -> StrResult<Content> {
+    let source = world.source(world.main()).map_err(|err| eco_format!("{err}"))?;
+    let traced = Traced::default();
+    let mut sink = Sink::new();
+    let module = typst::eval::eval(
+        world,
+        traced.track(),
+        sink.track_mut(),
+        Route::default().track(),
+        &source,
+    )
+    .map_err(|errors| join_errors("failed to evaluate document", errors))?;
+    Ok(module.content())
+}
+
+/// Split content into the plain text of its paragraphs.
+///
+/// The span of every footnote is recorded in `anchors` alongside the index of
+/// the paragraph it appears in, in document order. Footnote bodies don't
+/// contribute any text.
+fn collect_paragraphs(
+    content: &Content,
+    paragraphs: &mut Vec<EcoString>,
+    anchors: &mut Vec<(Span, usize)>,
+) {
+    if let Some(sequence) = content.to_packed::<SequenceElem>() {
+        for child in sequence.children() {
+            collect_paragraphs(child, paragraphs, anchors);
+        }
+    } else if let Some(styled) = content.to_packed::<StyledElem>() {
+        collect_paragraphs(styled.child(), paragraphs, anchors);
+    } else if content.is::<FootnoteElem>() {
+        anchors.push((content.span(), paragraphs.len() - 1));
+    } else if content.is::<ParbreakElem>() {
+        paragraphs.push(EcoString::new());
+    } else if content.is::<HeadingElem>() {
+        paragraphs.push(content.plain_text());
+        paragraphs.push(EcoString::new());
+    } else if content.is::<LinebreakElem>() {
+        paragraphs.last_mut().unwrap().push('\n');
+    } else {
+        paragraphs.last_mut().unwrap().push_str(&content.plain_text());
+        collect_anchors(content, paragraphs.len() - 1, anchors);
+    }
+}
+
+/// Record the footnotes within an element's fields, like in `strong`, as
+/// anchored in the given paragraph.
+fn collect_anchors(
+    content: &Content,
+    paragraph: usize,
+    anchors: &mut Vec<(Span, usize)>,
+) {
+    fn walk(value: Value, paragraph: usize, anchors: &mut Vec<(Span, usize)>) {
+        match value {
+            Value::Content(content) if content.is::<FootnoteElem>() => {
+                anchors.push((content.span(), paragraph));
+            }
+            Value::Content(content) => collect_anchors(&content, paragraph, anchors),
+            Value::Array(array) => {
+                for value in array {
+                    walk(value, paragraph, anchors);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (_, value) in content.fields() {
+        walk(value, paragraph, anchors);
+    }
+}
+
+/// Describe the entries of the document's bibliography by their key, prefix,
+/// and formatted text.
+fn bibliography_entries(world: &dyn World, document: &Document) -> StrResult<Array> {
+    let references =
+        BibliographyElem::references(world.track(), document.introspector.track())?;
+    Ok(references
+        .into_iter()
+        .map(|(key, prefix, reference)| {
+            dict! {
+                "key" => key,
+                "prefix" => prefix.map(|prefix| prefix.plain_text()),
+                "text" => reference.plain_text(),
+            }
+            .into_value()
+        })
+        .collect())
+}
+
+/// Describe the raw text in the document by its language, whether it is a
+/// block, and its text.
+///
+/// Raw text isn't locatable, so it can't be retrieved from the laid out
+/// document. Like `--text`, this works on the document's content before show
+/// rules are applied instead.
+fn raw_texts(world: &dyn World) -> StrResult<Array> {
+    let mut raws = Array::new();
+    collect_raws(&eval_main(world.track())?, &mut raws);
+    Ok(raws)
+}
+
+/// Describe every raw element in the content, in document order.
+fn collect_raws(content: &Content, raws: &mut Array) {
+    if let Some(raw) = content.to_packed::<RawElem>() {
+        let styles = StyleChain::default();
+        raws.push(
+            dict! {
+                "lang" => raw.lang(styles).clone(),
+                "block" => raw.block(styles),
+                "text" => raw.text().clone(),
+            }
+            .into_value(),
+        );
+        return;
+    }
+
+    fn walk(value: Value, raws: &mut Array) {
+        match value {
+            Value::Content(content) => collect_raws(&content, raws),
+            Value::Array(array) => {
+                for value in array {
+                    walk(value, raws);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (_, value) in content.fields() {
+        walk(value, raws);
+    }
+}
+
+/// Describe the document itself: Its pages and metadata.
+fn document_info(document: &Document) -> Dict {
+    let sizes: Array = document
+        .pages
+        .iter()
+        .map(|page| {
+            let size = page.frame.size();
+            dict! { "width" => size.x.to_pt(), "height" => size.y.to_pt() }.into_value()
+        })
+        .collect();
+    let strings = |strings: &[EcoString]| -> Array {
+        strings.iter().map(|s| s.as_str().into_value()).collect()
+    };
+
+    dict! {
+        "pages" => document.pages.len(),
+        "sizes" => sizes,
+        "title" => document.title.as_deref(),
+        "author" => strings(&document.author),
+        "keywords" => strings(&document.keywords),
+    }
+}
+
+/// Print how often each kind of element occurs in the document to stderr, as
+/// a table sorted by frequency.
+fn print_profile(document: &Document) {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for elem in document.introspector.all() {
+        *counts.entry(elem.func().name()).or_default() += 1;
+    }
+
+    let mut rows: Vec<_> = counts.into_iter().collect();
+    rows.sort_by(|(a, m), (b, n)| n.cmp(m).then_with(|| a.cmp(b)));
+
+    let width = rows.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    for (name, count) in rows {
+        eprintln!("{name:<width$}  {count:>8}");
+    }
+}
+
+/// Count the queryable elements in the document by their element function.
+fn count_elements(
+    command: &QueryCommand,
+    document: &Document,
+) -> BTreeMap<&'static str, usize> {
+    let ranges = command.page_ranges();
+    let mut counts = BTreeMap::new();
+    for elem in document.introspector.all() {
+        if on_pages(elem, ranges.as_ref(), document) {
+            *counts.entry(elem.func().name()).or_default() += 1;
+        }
+    }
+    counts
+}
+
+/// Find the locations of all elements that are hidden from view with `hide`.
+///
+/// Whether an element is hidden is decided in the evaluated main file, which
+/// lists the elements in the same order as the document. Elements created by
+/// the same code, like in a function that is called both within `hide` and
+/// outside of it, share a span, so the n-th laid out element with a span is
+/// matched up with the n-th evaluated one. Metadata doesn't count, as it is
+/// invisible anyway. This is memoized, so the document is only evaluated and
+/// walked once, however often it is queried.
+#[comemo::memoize]
+fn hidden_locations(
+    world: Tracked<dyn World + '_>,
+    introspector: Tracked<Introspector>,
+) -> StrResult<Arc<HashSet<Location>>> {
+    let mut occurrences = HashMap::new();
+    collect_hidden(&eval_main(world)?, false, &mut occurrences);
+
+    let mut seen = HashMap::<Span, usize>::new();
+    let hidden = introspector
+        .query(&Selector::can::<dyn Locatable>())
+        .into_iter()
+        .filter(|elem| {
+            let n = seen.entry(elem.span()).or_default();
+            let hidden = occurrences
+                .get(&elem.span())
+                .and_then(|hidden: &Vec<bool>| hidden.get(*n).copied())
+                .unwrap_or(false);
+            *n += 1;
+            hidden
+        })
+        .filter_map(|elem| elem.location())
+        .collect();
+    Ok(Arc::new(hidden))
+}
+
+/// Record for every locatable element in the content, in document order,
+/// whether it lies within `hide`.
+fn collect_hidden(
+    content: &Content,
+    hidden: bool,
+    occurrences: &mut HashMap<Span, Vec<bool>>,
+) {
+    let hidden = hidden || content.is::<HideElem>();
+    if content.can::<dyn Locatable>() {
+        let metadata = content.is::<MetadataElem>();
+        occurrences
+            .entry(content.span())
+            .or_default()
+            .push(hidden && !metadata);
+    }
+
+    // The value of metadata is never laid out, so any elements in it would
+    // throw off the order.
+    if content.is::<MetadataElem>() {
+        return;
+    }
+
+    fn walk(value: Value, hidden: bool, occurrences: &mut HashMap<Span, Vec<bool>>) {
+        match value {
+            Value::Content(content) => collect_hidden(&content, hidden, occurrences),
+            Value::Array(array) => {
+                for value in array {
+                    walk(value, hidden, occurrences);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    for (_, value) in content.fields() {
+        walk(value, hidden, occurrences);
+    }
+}
+
+/// Whether an element lies within the page ranges, if any.
+fn on_pages(elem: &Content, ranges: Option<&PageRanges>, document: &Document) -> bool {
+    let Some(ranges) = ranges else { return true };
+    elem.location()
+        .is_some_and(|loc| ranges.includes_page(document.introspector.page(loc)))
+}
+
+/// Turn the query result into the value to serialize.
+fn format(
+    world: &SystemWorld,
+    document: &Document,
+    elements: Vec<Content>,
+    command: &QueryCommand,
+) -> StrResult<Value> {
+    let elements = match command.index {
+        Some(index) => vec![select_index(elements, index)?],
+        None if command.one => {
+            typst_ide::expect_one(&elements)?;
+            elements
+        }
+        None => elements,
+    };
+
+    if command.one || command.index.is_some() {
+        let Some(elem) = elements.into_iter().next() else {
+            bail!("no elements matched selector");
+        };
+        let value = map_element(world, document, elem, command)?;
+        if command.format == SerializationFormat::Toml
+            && !matches!(value, Value::Dict(_) | Value::Content(_))
+        {
+            bail!(
+                "cannot serialize {} as TOML, which requires a table at the top level",
+                value.ty()
+            );
+        }
+        Ok(value)
+    } else {
+        if command.format == SerializationFormat::Toml {
+            bail!(
+                "cannot serialize multiple elements as TOML, \
+                 which requires a table at the top level (try `--one`)"
+            );
+        }
+        Ok(Value::Array(
+            elements
+                .into_iter()
+                .filter_map(|elem| try_map_element(world, document, elem, command))
+                .collect(),
+        ))
+    }
+}
+
+/// Round numbers if requested with `--float-precision` and turn them into
+/// strings if requested with `--number-mode string`.
+fn apply_number_mode(value: Value, command: &QueryCommand) -> Value {
+    let value = match command.float_precision {
+        Some(precision) => round_numbers(value, precision),
+        None => value,
+    };
+    match command.number_mode {
+        NumberMode::Native => value,
+        NumberMode::String => stringify_numbers(value),
+    }
+}
+
+/// Recursively round floats and lengths to the given number of decimal
+/// places.
+///
+/// Integers are left as they are. Like for `stringify_numbers`, elements are
+/// turned into dictionaries of their fields.
+fn round_numbers(value: Value, precision: u8) -> Value {
+    let round = |v: f64| {
+        let offset = 10_f64.powi(precision.into());
+        (v * offset).round() / offset
+    };
+    let round_length = |length: Length| Length {
+        abs: Abs::pt(round(length.abs.to_pt())),
+        em: Em::new(round(length.em.get())),
+    };
+
+    match value {
+        Value::Float(v) => Value::Float(round(v)),
+        Value::Length(v) => Value::Length(round_length(v)),
+        Value::Relative(v) => Value::Relative(Rel::new(v.rel, round_length(v.abs))),
+        Value::Array(array) => array
+            .into_iter()
+            .map(|value| round_numbers(value, precision))
+            .collect::<Array>()
+            .into_value(),
+        Value::Dict(dict) => dict
+            .into_iter()
+            .map(|(key, value)| (key, round_numbers(value, precision)))
+            .collect::<Dict>()
+            .into_value(),
+        Value::Content(elem) => {
+            let mut dict = dict! { "func" => elem.func().name() };
+            dict.extend(elem.fields());
+            round_numbers(dict.into_value(), precision)
+        }
+        value => value,
+    }
+}
+
+/// Recursively replace integers and floats with their decimal representation.
+///
+/// Elements are turned into dictionaries of their fields for this, which
+/// serialize just like the elements themselves.
+fn stringify_numbers(value: Value) -> Value {
+    match value {
+        Value::Int(v) => Value::Str(eco_format!("{v}").into()),
+        Value::Float(v) => Value::Str(eco_format!("{v}").into()),
+        Value::Array(array) => array
+            .into_iter()
+            .map(stringify_numbers)
+            .collect::<Array>()
+            .into_value(),
+        Value::Dict(dict) => dict
+            .into_iter()
+            .map(|(key, value)| (key, stringify_numbers(value)))
+            .collect::<Dict>()
+            .into_value(),
+        Value::Content(elem) => {
+            let mut dict = dict! { "func" => elem.func().name() };
+            dict.extend(elem.fields());
+            stringify_numbers(dict.into_value())
+        }
+        value => value,
+    }
+}
+
+/// Pick the element at `index`, counting from the end if it is negative.
+fn select_index(mut elements: Vec<Content>, index: i64) -> StrResult<Content> {
+    let len = elements.len();
+    let resolved = if index < 0 {
+        i64::try_from(len).ok().and_then(|len| len.checked_add(index))
+    } else {
+        Some(index)
+    };
+
+    match resolved.and_then(|i| usize::try_from(i).ok()).filter(|&i| i < len) {
+        Some(i) => Ok(elements.swap_remove(i)),
+        None => bail!("index {index} is out of bounds for {len} retrieved elements"),
+    }
+}
+
+/// Turn an element into the value to serialize for it like `map_element`,
+/// unless it doesn't have the requested field.
+///
+/// When several elements are output, those without the field are skipped
+/// instead of failing the whole query.
+fn try_map_element(
+    world: &SystemWorld,
+    document: &Document,
+    elem: Content,
+    command: &QueryCommand,
+) -> Option<Value> {
+    map_element(world, document, elem, command).ok()
+}
+
+/// Whether `map_element` turns elements into a bare field value, which
+/// further information is attached to by wrapping it.
+///
+/// This is the case when a single field is extracted and nothing, like the
+/// location, was attached to it yet.
+fn maps_to_field(command: &QueryCommand) -> bool {
+    command.field.len() == 1
+        && !command.with_location
+        && !command.with_package
+        && !command.with_ref
+        && !command.with_hash
+}
+
+/// Turn a single element into the value to serialize for it, as requested by
+/// `--field` and `--with-location`.
+fn map_element(
+    world: &SystemWorld,
+    document: &Document,
+    elem: Content,
+    command: &QueryCommand,
+) -> StrResult<Value> {
+    let introspector = &document.introspector;
+    let location = command.with_location.then(|| locate(world, introspector, &elem));
+    let package = command.with_package.then(|| package_origin(&elem));
+    let reference = command
+        .with_ref
+        .then(|| footnote_reference(world, introspector, &elem))
+        .transpose()?;
+    let resolve =
+        |elem: Content, field: &str| match resolve_field(elem, field, introspector) {
+            Err(_) if command.null_missing_field => Ok(Value::None),
+            result => result,
+        };
+
+    let mut value = match command.field.as_slice() {
+        [] if !command.only_fields.is_empty() => {
+            let fields = elem.fields();
+            let mut dict = dict! { "func" => elem.func().name() };
+            for name in &command.only_fields {
+                if let Ok(value) = fields.get(name) {
+                    dict.insert(name.as_str().into(), value.clone());
+                }
+            }
+            dict.into_value()
+        }
+        [] => elem.into_value(),
+        [field] => resolve(elem, field)?,
+        fields => fields
+            .iter()
+            .map(|field| {
+                let value = resolve(elem.clone(), field)?;
+                Ok((field.as_str().into(), value))
+            })
+            .collect::<StrResult<Dict>>()?
+            .into_value(),
+    };
+
+    let hash = command.with_hash.then(|| content_hash(&value)).transpose()?;
+
+    // Once a single field was wrapped, further information goes next to it.
+    let mut is_field = command.field.len() == 1;
+    for (key, extra) in [
+        ("location", location.map(Dict::into_value)),
+        ("package", package),
+        ("reference", reference),
+        ("hash", hash),
+    ] {
+        if let Some(extra) = extra {
+            value = attach(value, key, extra, is_field);
+            is_field = false;
+        }
+    }
+    Ok(value)
+}
+
+/// Hash the serialized form of a value, as requested by `--with-hash`.
+///
+/// The JSON serialization is hashed with a fixed key, so the hash doesn't
+/// depend on the process or the platform.
+fn content_hash(value: &Value) -> StrResult<Value> {
+    let bytes = serde_json::to_vec(value)
+        .map_err(|err| eco_format!("failed to hash element ({err})"))?;
+    let mut state = SipHasher13::new();
+    state.write(&bytes);
+    Ok(eco_format!("{:032x}", state.finish128().as_u128()).into_value())
+}
+
+/// Describe the package an element stems from, or `none` if it stems from the
+/// project itself.
+fn package_origin(elem: &Content) -> Value {
+    match elem.span().id().and_then(|id| id.package()) {
+        Some(spec) => dict! {
+            "namespace" => spec.namespace.clone(),
+            "name" => spec.name.clone(),
+            "version" => spec.version.to_string(),
+        }
+        .into_value(),
+        None => Value::None,
+    }
+}
+
+/// Describe where an element lives, both in the document and in the source.
+fn locate(world: &SystemWorld, introspector: &Introspector, elem: &Content) -> Dict {
+    let page = elem.location().map(|loc| introspector.page(loc));
+    let span = elem.span();
+    let file = span
+        .id()
+        .and_then(|id| codespan_reporting::files::Files::name(world, id).ok());
+    let (line, column) = span
+        .id()
+        .and_then(|id| world.source(id).ok())
+        .and_then(|source| {
+            let start = source.range(span)?.start;
+            Some((source.byte_to_line(start)? + 1, source.byte_to_column(start)? + 1))
+        })
+        .unzip();
+
+    dict! {
+        "page" => page,
+        "file" => file,
+        "line" => line,
+        "column" => column,
+        "id" => elem.location().map(location_id),
+    }
+}
+
+/// Describe each match on a line, as requested by `--summary`.
+///
+/// Given a `width`, only the text preview is cut short to fit it. The page is
+/// `-` for elements without a location.
+fn summarize(document: &Document, elements: &[Content], width: Option<usize>) -> String {
+    let mut out = String::new();
+    for elem in elements {
+        let mut line = match elem.location() {
+            Some(loc) => format!("{}:", document.introspector.page(loc)),
+            None => "-:".into(),
+        };
+        line.push_str(elem.func().name());
+        if let Some(label) = elem.label() {
+            line.push_str(&format!(" <{}>", label.as_str()));
+        }
+
+        let text = elem.plain_text();
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if !text.is_empty() {
+            line.push_str(" — ");
+            let room = width
+                .map_or(usize::MAX, |width| width.saturating_sub(line.chars().count()));
+            if text.chars().count() <= room {
+                line.push_str(&text);
+            } else {
+                line.extend(text.chars().take(room.saturating_sub(1)));
+                line.push('…');
+            }
+        }
+
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
+
+/// Describe each match for a human reader, as requested by `--explain`.
+///
+/// For every element, this lists its function, label, page, and source
+/// position, followed by the first line of the source text it stems from.
+fn explain(world: &SystemWorld, document: &Document, elements: &[Content]) -> String {
+    let mut out = String::new();
+    for elem in elements {
+        out.push_str(elem.func().name());
+        if let Some(label) = elem.label() {
+            out.push_str(&eco_format!(" <{}>", label.as_str()));
+        }
+        if let Some(loc) = elem.location() {
+            out.push_str(&eco_format!(", page {}", document.introspector.page(loc)));
+        }
+
+        let matched = typst_ide::match_source_range(world, elem);
+        match matched.and_then(|(id, range)| Some((world.source(id).ok()?, range))) {
+            Some((source, range)) => {
+                let name = codespan_reporting::files::Files::name(world, source.id())
+                    .unwrap_or_default();
+                let line = source.byte_to_line(range.start).unwrap_or_default() + 1;
+                let column = source.byte_to_column(range.start).unwrap_or_default() + 1;
+                out.push_str(&eco_format!(", {name}:{line}:{column}\n"));
+                let text = source.text()[range].lines().next().unwrap_or_default();
+                out.push_str(&eco_format!("  {}\n", text.trim()));
+            }
+            None => out.push_str(", not from a source file\n"),
+        }
+    }
+    out
+}
+
+/// Describe the serialized form of the retrieved elements as a JSON Schema.
+fn schema(elements: &[Content], command: &QueryCommand) -> serde_json::Value {
+    let mut funcs = vec![];
+    for elem in elements {
+        if !funcs.contains(&elem.elem()) {
+            funcs.push(elem.elem());
+        }
+    }
+
+    let mut alternatives: Vec<_> = funcs.into_iter().map(element_schema).collect();
+    let item = if alternatives.len() == 1 {
+        alternatives.remove(0)
+    } else {
+        serde_json::json!({ "anyOf": alternatives })
+    };
+
+    let mut schema = if command.one || command.index.is_some() {
+        item
+    } else {
+        serde_json::json!({ "type": "array", "items": item })
+    };
+    schema["$schema"] = "https://json-schema.org/draft/2020-12/schema".into();
+    schema
+}
+
+/// Describe the serialized form of an element as a JSON Schema.
+fn element_schema(elem: Element) -> serde_json::Value {
+    let mut properties = serde_json::Map::new();
+    properties.insert("func".into(), serde_json::json!({ "const": elem.name() }));
+    properties.insert("label".into(), serde_json::json!({ "type": "string" }));
+
+    let mut required = vec!["func"];
+    for param in elem.params() {
+        let mut schema = cast_schema(&param.input);
+        if param.variadic {
+            schema = serde_json::json!({ "type": "array", "items": schema });
+        }
+        properties.insert(param.name.into(), schema);
+        if param.required {
+            required.push(param.name);
+        }
+    }
+
+    serde_json::json!({
+        "title": elem.title(),
+        "type": "object",
+        "properties": properties,
+        "required": required,
+    })
+}
+
+/// Describe the serialized form of the values a field accepts.
+///
+/// Values without a serialized form of their own, like functions or lengths,
+/// are serialized as their code representation.
+fn cast_schema(info: &CastInfo) -> serde_json::Value {
+    match info {
+        CastInfo::Any => serde_json::json!({}),
+        CastInfo::Value(value, _) => match serde_json::to_value(value) {
+            Ok(value) => serde_json::json!({ "const": value }),
+            Err(_) => serde_json::json!({}),
+        },
+        CastInfo::Type(ty) => {
+            let name = if *ty == Type::of::<NoneValue>() {
+                "null"
+            } else if *ty == Type::of::<bool>() {
+                "boolean"
+            } else if *ty == Type::of::<i64>() {
+                "integer"
+            } else if *ty == Type::of::<f64>() {
+                "number"
+            } else if *ty == Type::of::<Array>() {
+                "array"
+            } else if *ty == Type::of::<Content>() || *ty == Type::of::<Dict>() {
+                "object"
+            } else {
+                "string"
+            };
+            serde_json::json!({ "type": name })
+        }
+        CastInfo::Union(infos) => {
+            let alternatives: Vec<_> = infos.iter().map(cast_schema).collect();
+            serde_json::json!({ "anyOf": alternatives })
+        }
+    }
+}
+
+/// Attach extra information, like an element's location, to its serialized
+/// value under the given key.
+///
+/// Whole elements and sets of several extracted fields gain the key next to
+/// their fields. A single extracted field value is wrapped as
+/// `(value: .., <key>: ..)` instead.
+fn attach(value: Value, key: &str, extra: Value, is_field: bool) -> Value {
+    let mut dict = match value {
+        Value::Content(elem) if !is_field => {
+            let mut dict = dict! { "func" => elem.func().name() };
+            dict.extend(elem.fields());
+            dict
+        }
+        Value::Dict(dict) if !is_field => dict,
+        value => dict! { "value" => value },
+    };
+    dict.insert(key.into(), extra);
+    dict.into_value()
+}
+
+/// Serialize data straight into the output, which is stdout by default.
+fn write(data: &impl Serialize, command: &QueryCommand) -> HintedStrResult<()> {
+    if command.format.is_binary()
+        && command.output.is_none()
+        && io::stdout().is_terminal()
+    {
+        bail!(
+            "refusing to write binary data to the terminal";
+            hint: "use `--output` to write to a file, or `--output -` \
+                   to write to stdout anyway"
+        );
+    }
+
+    if let Some(baseline) = &command.baseline {
+        let mut buf = vec![];
+        serialize_output(data, command, &mut buf)?;
+        return compare_baseline(baseline, &buf, command);
+    }
+
+    let output = command.output.clone().unwrap_or(Output::Stdout);
+    create_parent_dir(&output)?;
+
+    let mut writer = output
+        .open()
+        .map_err(|err| eco_format!("failed to open query output ({err})"))?;
+    serialize_output(data, command, &mut writer)?;
+    writer
+        .flush()
+        .map_err(|err| eco_format!("failed to write query output ({err})"))?;
+
+    Ok(())
+}
+
+/// Write output that is already in its final form, like the plain text of
+/// `--count` or `--summary`, into the output, which is stdout by default.
+fn write_output(command: &QueryCommand, bytes: &[u8]) -> HintedStrResult<()> {
+    if let Some(baseline) = &command.baseline {
+        return compare_baseline(baseline, bytes, command);
+    }
+
+    let output = command.output.clone().unwrap_or(Output::Stdout);
+    create_parent_dir(&output)?;
+    output
+        .write(bytes)
+        .map_err(|err| eco_format!("failed to write query output ({err})"))?;
+    Ok(())
+}
+
+/// Serialize data in the requested format, ending text with a newline.
+fn serialize_output(
+    data: &impl Serialize,
+    command: &QueryCommand,
+    writer: &mut dyn Write,
+) -> StrResult<()> {
+    timed(command, "serialize", || {
+        serialize(data, command.format, command.layout(), &mut *writer)
+    })?;
+    if !command.format.is_binary() && !command.format.ends_lines() {
+        writeln!(writer)
+            .map_err(|err| eco_format!("failed to write query output ({err})"))?;
+    }
+    Ok(())
+}
+
+/// Compare serialized output against the `--baseline` file, or overwrite the
+/// file with it if `--update-baseline` is given.
+///
+/// On a mismatch, a line diff is printed to stderr for text formats.
+fn compare_baseline(
+    baseline: &Path,
+    output: &[u8],
+    command: &QueryCommand,
+) -> HintedStrResult<()> {
+    if command.update_baseline {
+        create_parent_dir(&Output::Path(baseline.into()))?;
+        fs::write(baseline, output)
+            .map_err(|err| eco_format!("failed to write baseline ({err})"))?;
+        return Ok(());
+    }
+
+    let expected = fs::read(baseline).map_err(|err| {
+        eco_format!(
+            "failed to read baseline {} ({err}), \
+             pass `--update-baseline` to create it",
+            baseline.display()
+        )
+    })?;
+    if expected == output {
+        return Ok(());
+    }
+
+    if !command.format.is_binary() {
+        let expected = String::from_utf8_lossy(&expected);
+        let output = String::from_utf8_lossy(output);
+        eprint!("{}", diff_lines(&expected, &output));
+    }
+    bail!("query output does not match baseline {}", baseline.display());
+}
+
+/// Compare two texts line by line.
+///
+/// Lines only in the old text are marked with `-`, lines only in the new one
+/// with `+`, and lines in both are indented by a space.
+fn diff_lines(old: &str, new: &str) -> String {
+    let old: Vec<_> = old.lines().collect();
+    let new: Vec<_> = new.lines().collect();
+
+    // The length of the longest common subsequence of each pair of suffixes.
+    let mut lcs = vec![vec![0; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() || j < new.len() {
+        let (mark, line) = if i < old.len() && j < new.len() && old[i] == new[j] {
+            i += 1;
+            j += 1;
+            (' ', old[i - 1])
+        } else if j < new.len() && (i == old.len() || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            j += 1;
+            ('+', new[j - 1])
+        } else {
+            i += 1;
+            ('-', old[i - 1])
+        };
+        diff.push(mark);
+        diff.push_str(line);
+        diff.push('\n');
+    }
+    diff
+}
+
+/// Resolve a dot-separated field path like `value.title` on an element.
+///
+/// If the element has no field of the first segment's name, the segment may
+/// also name one of these pseudo-fields:
+/// - `text`: The element's plain text.
+/// - `location`: The element's page and position on it.
+///
+/// The element's `label` is available like any other field.
+fn resolve_field(
+    elem: Content,
+    path: &str,
+    introspector: &Introspector,
+) -> StrResult<Value> {
+    let error = |segment: &str, err: EcoString| {
+        eco_format!("failed to resolve `{segment}` in field path `{path}` ({err})")
+    };
+
+    let mut segments = path.split('.');
+    let first = segments.next().unwrap_or_default();
+    let value = typst_ide::element_field(&elem, first)
+        .or_else(|err| pseudo_field(&elem, first, introspector).ok_or(err))
+        .map_err(|err| error(first, err.into()))?;
+
+    segments.try_fold(value, |value, segment| {
+        value.field(segment).map_err(|err| error(segment, err))
+    })
+}
+
+/// Resolve one of the pseudo-fields documented on [`resolve_field`].
+fn pseudo_field(
+    elem: &Content,
+    name: &str,
+    introspector: &Introspector,
+) -> Option<Value> {
+    match name {
+        "text" => Some(elem.plain_text().into_value()),
+        "location" => Some(introspector.position(elem.location()?).into_value()),
+        _ => None,
+    }
+}
+
+/// Lay out matched elements on their own and write them to the output as
+/// images.
+///
+/// If the output path contains `{n}`, every match is written to its own file
+/// with `{n}` replaced by the match's index. With `--data-uri`, every match is
+/// printed as a data URI on its own line. Otherwise, only the first match is
+/// rendered.
+fn render(
+    world: &SystemWorld,
+    document: &Document,
+    elements: &[Content],
+    command: &QueryCommand,
+) -> StrResult<()> {
+    if command.one {
+        typst_ide::expect_one(elements)?;
+    }
+
+    if elements.is_empty() {
+        bail!("selector did not match any element, nothing to render");
+    }
+
+    let background = command.background()?;
+    let output = match command.data_uri {
+        Some(_) => Output::Stdout,
+        None => command.render_output()?,
+    };
+    let template = match &output {
+        Output::Path(path) => path.to_str().filter(|path| path.contains("{n}")),
+        Output::Stdout => None,
+    };
+
+    let all = template.is_some() || command.data_uri.is_some();
+    let elements = if all || command.output_dir.is_some() {
+        elements
+    } else {
+        if elements.len() > 1 {
+            let warning = warning!(
+                Span::detached(),
+                "selector matched {} elements, but only the first one is rendered",
+                elements.len();
+                hint: "add `{{n}}` to the output path to render all of them"
+            );
+            print_diagnostics(world, &[], &[warning], command.common.diagnostic_format)
+                .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+        }
+        &elements[..1]
+    };
+
+    // All matches are laid out with the same engine.
+    let library = world.library();
+    let styles = StyleChain::new(&library.styles);
+    let traced = Traced::default();
+    let mut sink = Sink::new();
+    let mut engine = layout_engine(world, document, &traced, &mut sink);
+
+    for (i, elem) in elements.iter().enumerate() {
+        let fit = fit_styles(document, elem, command.fit);
+        let mut rendered = elem
+            .layout_document(&mut engine, styles.chain(&fit))
+            .map_err(|errors| join_errors("failed to render element", errors))?;
+
+        let n = command.render_page.get();
+        if n > rendered.pages.len() {
+            bail!(
+                "cannot render page {n} of element, as it only spans {} page(s)",
+                rendered.pages.len()
+            );
+        }
+
+        let output = match (&command.output_dir, template) {
+            (Some(dir), _) => {
+                let introspector = &document.introspector;
+                let name =
+                    fill_name_template(&command.name_template, i, elem, introspector);
+                Output::Path(dir.join(name))
+            }
+            (None, Some(template)) => {
+                Output::Path(template.replace("{n}", &i.to_string()).into())
+            }
+            (None, None) => output.clone(),
+        };
+
+        let mut page = rendered.pages.swap_remove(n - 1);
+        if command.emit_geometry {
+            eprintln!("{}", geometry(&page.frame, &output));
+        }
+        if let Some(color) = background {
+            page.frame.fill(color.into());
+        }
+
+        // A PDF is titled after the element's label by default.
+        let title = rendered.title.or_else(|| elem.label().map(|l| l.as_str().into()));
+        let document = Document { pages: vec![page], title, ..rendered };
+
+        let format = command.render_format();
+        let buf = encode_rendered(&document, format, command)?;
+        if command.data_uri.is_some() {
+            println!(
+                "data:{};base64,{}",
+                format.mime_type(),
+                BASE64_STANDARD.encode(buf)
+            );
+        } else {
+            create_parent_dir(&output)?;
+            output.write(&buf).map_err(|err| {
+                eco_format!("failed to write {} file ({err})", format.name())
+            })?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Fill in the placeholders of `--name-template` for a rendered element.
+fn fill_name_template(
+    template: &str,
+    index: usize,
+    elem: &Content,
+    introspector: &Introspector,
+) -> String {
+    let label = match elem.label() {
+        Some(label) => label
+            .as_str()
+            .chars()
+            .map(|c| if c.is_alphanumeric() || "-_.".contains(c) { c } else { '_' })
+            .collect(),
+        None => format!("unlabeled-{index}"),
+    };
+    let page = elem.location().map_or(0, |loc| introspector.page(loc).get());
+    template
+        .replace("{index}", &index.to_string())
+        .replace("{label}", &label)
+        .replace("{page}", &page.to_string())
+}
+
+/// Describe the geometry of a rendered frame as a line of JSON.
+fn geometry(frame: &Frame, output: &Output) -> serde_json::Value {
+    serde_json::json!({
+        "output": output.to_string(),
+        "width": frame.width().to_pt(),
+        "height": frame.height().to_pt(),
+        "baseline": frame.baseline().to_pt(),
+        "items": frame.items().len(),
+    })
+}
+
+/// Page styles that size the page of a rendered element as requested with
+/// `--fit`.
+fn fit_styles(document: &Document, elem: &Content, fit: RenderFit) -> Styles {
+    let mut styles = Styles::new();
+    match fit {
+        RenderFit::Page => {
+            let index =
+                elem.location().map(|loc| document.introspector.page(loc).get() - 1);
+            if let Some(page) = index.and_then(|i| document.pages.get(i)) {
+                let size = page.frame.size();
+                styles.set(PageElem::set_width(Smart::Custom(size.x.into())));
+                styles.set(PageElem::set_height(Smart::Custom(size.y.into())));
+            }
+        }
+        RenderFit::Content => {
+            styles.set(PageElem::set_width(Smart::Auto));
+            styles.set(PageElem::set_height(Smart::Auto));
+            styles.set(PageElem::set_margin(Margin::splat(Some(Smart::Custom(
+                Abs::zero().into(),
+            )))));
+        }
+    }
+    styles
+}
+
+/// Set up an engine for laying out elements of an already compiled document.
+fn layout_engine<'a>(
+    world: &'a dyn World,
+    document: &'a Document,
+    traced: &'a Traced,
+    sink: &'a mut Sink,
+) -> Engine<'a> {
+    Engine {
+        world: world.track(),
+        introspector: document.introspector.track(),
+        traced: traced.track(),
+        sink: sink.track_mut(),
+        route: Route::default(),
+    }
+}
+
+/// Encode a single rendered page in the requested format.
+fn encode_rendered(
+    document: &Document,
+    format: RenderFormat,
+    command: &QueryCommand,
+) -> StrResult<Vec<u8>> {
+    let frame = &document.pages[0].frame;
+    Ok(match format {
+        RenderFormat::Png => {
+            let fill = Color::WHITE.with_alpha(0.0);
+            let pixmap = typst_render::render(frame, command.ppi / 72.0, fill);
+            pixmap
+                .encode_png()
+                .map_err(|err| eco_format!("failed to encode PNG file ({err})"))?
+        }
+        RenderFormat::Svg => typst_svg::svg(frame).into_bytes(),
+        RenderFormat::Pdf => {
+            let timestamp = convert_datetime(
+                command.common.creation_timestamp.unwrap_or_else(chrono::Utc::now),
+            );
+            typst_pdf::pdf(document, Smart::Auto, timestamp, None)
+        }
+    })
+}
+
+/// A format to render matched elements in.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum RenderFormat {
+    Png,
+    Svg,
+    Pdf,
+}
+
+impl RenderFormat {
+    /// The name of the format, as used in messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Png => "PNG",
+            Self::Svg => "SVG",
+            Self::Pdf => "PDF",
+        }
+    }
+
+    /// The MIME type of the format, as used in data URIs.
+    pub fn mime_type(self) -> &'static str {
+        match self {
+            Self::Png => "image/png",
+            Self::Svg => "image/svg+xml",
+            Self::Pdf => "application/pdf",
+        }
+    }
+}
+
+/// Create the parent directories of the output path if they don't exist yet.
+fn create_parent_dir(output: &Output) -> StrResult<()> {
+    if let Output::Path(path) = output {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| {
+                eco_format!("failed to create output directory ({err})")
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Join the messages of multiple errors into a single message.
+fn join_errors(prefix: &str, errors: EcoVec<SourceDiagnostic>) -> EcoString {
+    let mut message = EcoString::from(prefix);
+    for (i, error) in errors.into_iter().enumerate() {
+        message.push_str(if i == 0 { ": " } else { ", " });
+        message.push_str(&error.message);
+    }
+    message
+}
+
+/// How much whitespace text formats are serialized with.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Layout {
+    /// The format's usual layout: JSON and TOML on as few lines as possible,
+    /// YAML in block style.
+    Default,
+    /// Pretty-printed, as requested by `--pretty`.
+    Pretty,
+    /// As compact as possible, as requested by `--compact`.
+    Compact,
+}
+
+impl SerializationFormat {
+    /// Whether the format produces binary rather than textual data.
+    fn is_binary(self) -> bool {
+        matches!(self, Self::Cbor | Self::MsgPack)
+    }
+
+    /// Whether the format ends every line itself, including the last one.
+    fn ends_lines(self) -> bool {
+        matches!(self, Self::Jsonl | Self::Csv)
     }
 }
 
-/// Serialize data to the output format.
+/// Serialize data in the output format into a writer.
 fn serialize(
     data: &impl Serialize,
     format: SerializationFormat,
-    pretty: bool,
-) -> StrResult<String> {
+    layout: Layout,
+    writer: &mut dyn Write,
+) -> StrResult<()> {
+    let pretty = layout == Layout::Pretty;
     match format {
         SerializationFormat::Json => {
             if pretty {
-                serde_json::to_string_pretty(data).map_err(|e| eco_format!("{e}"))
+                serde_json::to_writer_pretty(writer, data).map_err(|e| eco_format!("{e}"))
             } else {
-                serde_json::to_string(data).map_err(|e| eco_format!("{e}"))
+                serde_json::to_writer(writer, data).map_err(|e| eco_format!("{e}"))
+            }
+        }
+        SerializationFormat::Jsonl => {
+            // Each element of an array goes on its own line. Anything else is
+            // a single line on its own.
+            let lines = match to_ordered_value(data)? {
+                Value::Array(items) => items.into_iter().collect(),
+                value => vec![value],
+            };
+            for line in lines {
+                serde_json::to_writer(&mut *writer, &line)
+                    .map_err(|e| eco_format!("{e}"))?;
+                writeln!(writer).map_err(|e| eco_format!("{e}"))?;
             }
+            Ok(())
         }
         SerializationFormat::Yaml => {
-            serde_yaml::to_string(data).map_err(|e| eco_format!("{e}"))
+            if layout == Layout::Compact {
+                // YAML is a superset of JSON, so compact JSON doubles as YAML
+                // in flow style.
+                serde_json::to_writer(writer, data).map_err(|e| eco_format!("{e}"))
+            } else {
+                serde_yaml::to_writer(writer, data).map_err(|e| eco_format!("{e}"))
+            }
+        }
+        SerializationFormat::Toml => {
+            // TOML can't be streamed, as tables must come after plain values.
+            let string =
+                if pretty { toml::to_string_pretty(data) } else { toml::to_string(data) }
+                    .map_err(|e| eco_format!("{e}"))?;
+            writer.write_all(string.as_bytes()).map_err(|e| eco_format!("{e}"))
+        }
+        SerializationFormat::Cbor => {
+            ciborium::into_writer(data, writer).map_err(|e| eco_format!("{e}"))
+        }
+        SerializationFormat::MsgPack => {
+            rmp_serde::encode::write_named(writer, data).map_err(|e| eco_format!("{e}"))
+        }
+        SerializationFormat::Csv => serialize_csv(data, writer),
+    }
+}
+
+/// Serialize data as CSV, with one row per element of an array.
+///
+/// All rows must be flat dictionaries with the same keys, which make up the
+/// header row.
+fn serialize_csv(data: &impl Serialize, writer: &mut dyn Write) -> StrResult<()> {
+    let rows = match to_ordered_value(data)? {
+        Value::Array(items) => items.into_iter().collect(),
+        value => vec![value],
+    };
+
+    let mut csv = csv::Writer::from_writer(writer);
+    let mut header: Option<Vec<String>> = None;
+    for row in rows {
+        let Value::Dict(dict) = row else {
+            bail!(
+                "cannot serialize as CSV, which requires every element to be a \
+                 dictionary (try `--field` or `--format json`)"
+            );
+        };
+
+        let keys: Vec<_> = dict.iter().map(|(key, _)| key.to_string()).collect();
+        match &header {
+            None => {
+                csv.write_record(&keys).map_err(|e| eco_format!("{e}"))?;
+                header = Some(keys);
+            }
+            Some(header) if *header != keys => bail!(
+                "cannot serialize dictionaries with differing keys as CSV \
+                 (try `--format json`)"
+            ),
+            Some(_) => {}
+        }
+
+        let cells =
+            dict.iter().map(|(_, v)| csv_cell(v)).collect::<StrResult<Vec<_>>>()?;
+        csv.write_record(&cells).map_err(|e| eco_format!("{e}"))?;
+    }
+
+    csv.flush().map_err(|e| eco_format!("{e}"))
+}
+
+/// Turn a value into the text of a CSV cell, if it is flat.
+fn csv_cell(value: &Value) -> StrResult<String> {
+    Ok(match value {
+        Value::None => String::new(),
+        Value::Bool(b) => b.to_string(),
+        Value::Int(n) => n.to_string(),
+        Value::Float(n) => n.to_string(),
+        Value::Str(s) => s.to_string(),
+        _ => bail!("cannot serialize nested values as CSV (try `--format json`)"),
+    })
+}
+
+/// Turn serializable data into a value, keeping the order of dictionary keys.
+///
+/// This goes through JSON instead of `serde_json::Value`, whose maps sort
+/// their keys.
+fn to_ordered_value(data: &impl Serialize) -> StrResult<Value> {
+    let json = serde_json::to_vec(data).map_err(|e| eco_format!("{e}"))?;
+    serde_json::from_slice(&json).map_err(|e| eco_format!("{e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsStr;
+
+    use clap::Parser;
+    use typst::foundations::{array, dict};
+    use typst::text::TextElem;
+    use typst::visualize::ImageElem;
+    use typst_ide::tests::TestWorld;
+
+    use super::*;
+
+    /// Retrieve the elements that `typst query -` with the given arguments
+    /// finds in a document compiled from the world.
+    fn retrieve_with(
+        world: &TestWorld,
+        document: &Document,
+        args: &[&str],
+    ) -> Vec<Content> {
+        let args = ["query", "-"].into_iter().chain(args.iter().copied());
+        let command = QueryCommand::try_parse_from(args).unwrap();
+        let Selectors::Single(selector) = eval_selectors(world, &command).unwrap() else {
+            panic!("expected a single selector");
+        };
+        retrieve(world, &command, document, &selector)
+    }
+
+    #[test]
+    fn test_dedup_running_header() {
+        let header = |page| {
+            MetadataElem::new("Chapter 1".into_value())
+                .pack()
+                .located(Location::new(page))
+        };
+        let other = MetadataElem::new("Chapter 2".into_value()).pack();
+        let mut elements = vec![header(1), header(2), other.clone(), header(3)];
+        dedup(&mut elements);
+        assert_eq!(elements, [header(1), other]);
+        assert_eq!(elements[0].location(), Some(Location::new(1)));
+    }
+
+    #[test]
+    fn test_select_index() {
+        let elements: Vec<_> =
+            (0..3_i64).map(|i| MetadataElem::new(i.into_value()).pack()).collect();
+        let select = |index| select_index(elements.clone(), index);
+        assert_eq!(select(0).unwrap(), elements[0]);
+        assert_eq!(select(2).unwrap(), elements[2]);
+        assert_eq!(select(-1).unwrap(), elements[2]);
+        assert_eq!(select(-3).unwrap(), elements[0]);
+        assert!(select(3).is_err());
+        assert!(select(-4).is_err());
+    }
+
+    #[test]
+    fn test_query_with_root() {
+        // The main file lives below the root and imports a file at the root
+        // by its absolute path.
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let chapters = root.join("chapters");
+        fs::create_dir_all(&chapters).unwrap();
+        fs::write(root.join("lib.typ"), "#let note = metadata(\"root\")").unwrap();
+        let main = chapters.join("main.typ");
+        fs::write(&main, "#import \"/lib.typ\": note\n#note <note>").unwrap();
+
+        let command = QueryCommand::try_parse_from([
+            OsStr::new("query"),
+            OsStr::new("--root"),
+            root.as_os_str(),
+            OsStr::new("--ignore-system-fonts"),
+            main.as_os_str(),
+            OsStr::new("<note>"),
+        ])
+        .unwrap();
+        let mut world = SystemWorld::new(&command.common).unwrap();
+        let selectors = eval_selectors(&world, &command).unwrap();
+        let document = compile_once(&mut world, &command).unwrap();
+
+        let (Some(document), Selectors::Single(selector)) = (document, selectors) else {
+            panic!("failed to compile document with root");
+        };
+        assert_eq!(retrieve(&world, &command, &document, &selector).len(), 1);
+    }
+
+    #[test]
+    fn test_nest() {
+        let nodes = [(1, "A"), (2, "B"), (3, "C"), (2, "D"), (1, "E"), (3, "F")];
+        let nodes = nodes.map(|(level, text)| (level, text.into_value()));
+        let node = |text: &str, children: Array| {
+            dict! { "value" => text, "children" => children }.into_value()
+        };
+        assert_eq!(
+            nest(&mut nodes.into_iter().peekable(), 0, true),
+            array![
+                node(
+                    "A",
+                    array![node("B", array![node("C", array![])]), node("D", array![])]
+                ),
+                node("E", array![node("F", array![])]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render_format() {
+        let format = |args: &[&str]| {
+            let args = ["query", "main.typ", "figure", "--render"].iter().chain(args);
+            QueryCommand::try_parse_from(args).unwrap().render_format()
+        };
+        assert_eq!(format(&[]), RenderFormat::Svg);
+        assert_eq!(format(&["--output", "out.PNG"]), RenderFormat::Png);
+        assert_eq!(format(&["--output", "out.pdf"]), RenderFormat::Pdf);
+        assert_eq!(format(&["--data-uri"]), RenderFormat::Svg);
+        assert_eq!(format(&["--data-uri", "png"]), RenderFormat::Png);
+        assert!(QueryCommand::try_parse_from([
+            "query",
+            "main.typ",
+            "figure",
+            "--data-uri"
+        ])
+        .is_err());
+    }
+
+    #[test]
+    fn test_fill_name_template() {
+        let world = TestWorld::new("= A <fig:intro>\n#pagebreak()\n= B");
+        let document = typst::compile(&world).output.unwrap();
+        let headings = document.introspector.query(&HeadingElem::elem().select());
+        let name = |i: usize| {
+            let template = "fig-{label}-{page}-{index}.svg";
+            fill_name_template(template, i, &headings[i], &document.introspector)
+        };
+        assert_eq!(name(0), "fig-fig_intro-1-0.svg");
+        assert_eq!(name(1), "fig-unlabeled-1-2-1.svg");
+    }
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(diff_lines("a\nb\nc\n", "a\nc\nd\n"), " a\n-b\n c\n+d\n");
+        assert_eq!(diff_lines("", "a"), "+a\n");
+    }
+
+    #[test]
+    fn test_baseline() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let baseline = root.join("headings.json");
+        let command = |update: bool| {
+            let mut args = vec![
+                OsStr::new("query"),
+                OsStr::new("-"),
+                OsStr::new("heading"),
+                OsStr::new("--baseline"),
+                baseline.as_os_str(),
+            ];
+            if update {
+                args.push(OsStr::new("--update-baseline"));
+            }
+            QueryCommand::try_parse_from(args).unwrap()
+        };
+
+        assert!(write(&array![1, 2], &command(false)).is_err());
+        write(&array![1, 2], &command(true)).unwrap();
+        assert!(write(&array![1, 2], &command(false)).is_ok());
+        assert!(write(&array![1, 3], &command(false)).is_err());
+    }
+
+    #[test]
+    fn test_baseline_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let baseline = dir.path().join("count.txt");
+        let mut args = vec![
+            OsStr::new("query"),
+            OsStr::new("-"),
+            OsStr::new("heading"),
+            OsStr::new("--count"),
+            OsStr::new("--baseline"),
+            baseline.as_os_str(),
+        ];
+        let compare = QueryCommand::try_parse_from(&args).unwrap();
+        args.push(OsStr::new("--update-baseline"));
+        let update = QueryCommand::try_parse_from(&args).unwrap();
+
+        write_output(&update, b"2\n").unwrap();
+        assert_eq!(fs::read_to_string(&baseline).unwrap(), "2\n");
+        assert!(write_output(&compare, b"2\n").is_ok());
+        assert!(write_output(&compare, b"3\n").is_err());
+    }
+
+    #[test]
+    fn test_summarize() {
+        let world =
+            TestWorld::new("= Introduction <intro>\n#pagebreak()\n= A longer title");
+        let document = typst::compile(&world).output.unwrap();
+        let headings = document.introspector.query(&HeadingElem::elem().select());
+        assert_eq!(
+            summarize(&document, &headings, Some(24)),
+            "1:heading <intro> — Int…\n2:heading — A longer ti…\n"
+        );
+        assert_eq!(
+            summarize(&document, &headings, None),
+            "1:heading <intro> — Introduction\n2:heading — A longer title\n"
+        );
+        let text = [TextElem::packed("a")];
+        assert_eq!(summarize(&document, &text, Some(20)), "-:text — a\n");
+    }
+
+    #[test]
+    fn test_envelope() {
+        let command = QueryCommand::try_parse_from(["query", "-", "heading"]).unwrap();
+        timed(&command, "compile", || ());
+        let envelope = envelope(array![1, 2].into_value(), 3, true);
+        assert_eq!(envelope.get("matches").unwrap(), &array![1, 2].into_value());
+
+        let meta = envelope.get("meta").unwrap().clone().cast::<Dict>().unwrap();
+        assert_eq!(meta.get("count").unwrap(), &3.into_value());
+        assert_eq!(meta.get("truncated").unwrap(), &true.into_value());
+        assert!(matches!(meta.get("compile_ms").unwrap(), Value::Float(_)));
+        assert_eq!(meta.get("retrieve_ms").unwrap(), &Value::None);
+
+        reset_phases();
+        let meta = envelope(array![].into_value(), 0, false).get("meta").unwrap().clone();
+        let meta = meta.cast::<Dict>().unwrap();
+        assert_eq!(meta.get("compile_ms").unwrap(), &Value::None);
+    }
+
+    #[test]
+    fn test_translate_css() {
+        let css = |text: &str| translate_css(text).unwrap();
+        assert_eq!(css("heading"), "heading");
+        assert_eq!(css("heading[level=1]"), "heading.where(level: 1)");
+        assert_eq!(
+            css("heading[level = 1][outlined=false]"),
+            "heading.where(level: 1, outlined: false)"
+        );
+        assert_eq!(css("figure.image"), "figure.where(kind: image)");
+        assert_eq!(css("metadata[value=\"a]\"]"), "metadata.where(value: \"a]\")");
+        assert_eq!(css("#intro"), "<intro>");
+        assert_eq!(css("heading#intro"), "selector(heading).and(<intro>)");
+        assert_eq!(css("heading, figure"), "selector(heading).or(figure)");
+
+        assert!(translate_css("heading > figure").is_err());
+        assert!(translate_css("heading[level]").is_err());
+        assert!(translate_css("heading[level=1").is_err());
+        assert!(translate_css(".image").is_err());
+        assert!(translate_css("").is_err());
+    }
+
+    #[test]
+    fn test_location_id_roundtrip() {
+        let world = TestWorld::new("= A\n= B <b>");
+        let document = typst::compile(&world).output.unwrap();
+        let b = document.introspector.query_label(Label::new("b")).unwrap();
+        let id = location_id(b.location().unwrap());
+        assert_eq!(retrieve_with(&world, &document, &["--location", &id]), [b.clone()]);
+        assert!(parse_location_id("not-an-id").is_err());
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = capabilities();
+        assert!(capabilities["formats"]
+            .as_array()
+            .unwrap()
+            .contains(&"msgpack".into()));
+        assert!(capabilities["options"].as_array().unwrap().contains(&"--tree".into()));
+        assert!(QueryCommand::try_parse_from(["query", "-", "--capabilities"]).is_ok());
+    }
+
+    #[test]
+    fn test_events_require_watch() {
+        assert!(QueryCommand::try_parse_from(["query", "a.typ", "heading", "--events"])
+            .is_err());
+        assert!(QueryCommand::try_parse_from([
+            "query", "a.typ", "heading", "--watch", "--events"
+        ])
+        .is_ok());
+    }
+
+    #[test]
+    fn test_also_conflicts() {
+        let parses = |args: &[&str]| {
+            let base = ["query", "a.typ", "heading", "--also", "b.typ"];
+            QueryCommand::try_parse_from(base.iter().chain(args)).is_ok()
+        };
+        assert!(parses(&["--unique"]));
+        assert!(!parses(&["--tree"]));
+        assert!(!parses(&["--key-by-label"]));
+        assert!(!parses(&["--group-by", "level"]));
+        assert!(!parses(&["--summary"]));
+        assert!(!parses(&["--envelope"]));
+    }
+
+    #[test]
+    fn test_input_pattern() {
+        let pattern = |input: &str| {
+            let command =
+                QueryCommand::try_parse_from(["query", input, "heading"]).unwrap();
+            command.input_pattern().map(str::to_owned)
+        };
+        assert_eq!(pattern("docs/**/*.typ").as_deref(), Some("docs/**/*.typ"));
+        assert_eq!(pattern("chapter-?.typ").as_deref(), Some("chapter-?.typ"));
+        assert_eq!(pattern("main.typ"), None);
+        assert_eq!(pattern("-"), None);
+    }
+
+    #[test]
+    fn test_glob_conflict() {
+        let conflict = |args: &[&str]| {
+            let args = ["query", "docs/*.typ", "heading"].iter().chain(args);
+            glob_conflict(&QueryCommand::try_parse_from(args).unwrap())
+        };
+        assert_eq!(conflict(&[]), None);
+        assert_eq!(conflict(&["--field", "body"]), None);
+        assert_eq!(conflict(&["--count"]), Some("--count"));
+        assert_eq!(conflict(&["--explain"]), Some("--explain"));
+        assert_eq!(conflict(&["--max-matches", "1"]), Some("--max-matches"));
+        assert_eq!(conflict(&["--errors-as-output"]), Some("--errors-as-output"));
+    }
+
+    #[test]
+    fn test_bibliography_entries() {
+        const WORKS: &str = "\
+netwok:
+  type: article
+  title: At-scale impact of the Net Wok
+  author: Astley, Rick
+  date: 2020
+arrgh:
+  type: book
+  title: The Pirate Organization
+  author: Leeson, Peter T.
+  date: 2009
+";
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(root.join("works.yml"), WORKS).unwrap();
+        let main = root.join("main.typ");
+        fs::write(
+            &main,
+            "@netwok\n#bibliography(\"works.yml\", style: \"ieee\", full: true)",
+        )
+        .unwrap();
+
+        let command = QueryCommand::try_parse_from([
+            OsStr::new("query"),
+            OsStr::new("--ignore-system-fonts"),
+            main.as_os_str(),
+            OsStr::new("--bibliography"),
+        ])
+        .unwrap();
+        let mut world = SystemWorld::new(&command.common).unwrap();
+        let document = compile_once(&mut world, &command).unwrap();
+        let entries = bibliography_entries(&world, &document.unwrap()).unwrap();
+
+        // With IEEE style, the cited work comes first.
+        let keys: Vec<_> = entries
+            .iter()
+            .map(|entry| {
+                entry.clone().cast::<Dict>().unwrap().get("key").unwrap().clone()
+            })
+            .collect();
+        assert_eq!(keys, ["netwok".into_value(), "arrgh".into_value()]);
+
+        let first = entries.first().unwrap().clone().cast::<Dict>().unwrap();
+        assert_eq!(first.get("prefix").unwrap(), &"[1]".into_value());
+        let text = first.get("text").unwrap().clone().cast::<EcoString>().unwrap();
+        assert!(text.contains("At-scale impact of the Net Wok"));
+    }
+
+    #[test]
+    fn test_query_session() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let main = root.join("main.typ");
+        fs::write(&main, "= A").unwrap();
+
+        let command = QueryCommand::try_parse_from([
+            OsStr::new("query"),
+            OsStr::new("--ignore-system-fonts"),
+            main.as_os_str(),
+            OsStr::new("heading"),
+        ])
+        .unwrap();
+        let Selectors::Single(selector) =
+            eval_selectors(&TestWorld::new(""), &command).unwrap()
+        else {
+            panic!("expected a single selector");
+        };
+
+        let mut session = open_session(&command).unwrap();
+        let count = |session: &mut QuerySession<SystemWorld>| {
+            refresh_session(session, &command).unwrap();
+            let document = session.document().unwrap();
+            retrieve(session.world(), &command, document, &selector).len()
+        };
+        assert_eq!(count(&mut session), 1);
+
+        // The document is kept until the session is invalidated.
+        fs::write(&main, "= A\n= B").unwrap();
+        assert_eq!(count(&mut session), 1);
+        session.invalidate();
+        assert_eq!(count(&mut session), 2);
+    }
+
+    #[test]
+    fn test_query_session_envelope() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let main = root.join("main.typ");
+        let output = root.join("headings.json");
+        fs::write(&main, "= A\n= B").unwrap();
+
+        let command = QueryCommand::try_parse_from([
+            OsStr::new("query"),
+            OsStr::new("--ignore-system-fonts"),
+            main.as_os_str(),
+            OsStr::new("heading"),
+            OsStr::new("--envelope"),
+            OsStr::new("--max-matches"),
+            OsStr::new("1"),
+            OsStr::new("--output"),
+            output.as_os_str(),
+        ])
+        .unwrap();
+
+        // The compilation in the refresh is reported, like while watching.
+        let mut session = open_session(&command).unwrap();
+        reset_phases();
+        refresh_session(&mut session, &command).unwrap();
+        query_session(&mut session, &command).unwrap();
+
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&fs::read(&output).unwrap()).unwrap();
+        assert!(envelope["meta"]["compile_ms"].is_f64());
+
+        // The count is taken before the results are capped.
+        assert_eq!(envelope["matches"].as_array().map(Vec::len), Some(1));
+        assert_eq!(envelope["meta"]["count"], 2);
+        assert_eq!(envelope["meta"]["truncated"], true);
+    }
+
+    #[test]
+    fn test_element_schema() {
+        let schema = element_schema(MetadataElem::elem());
+        assert_eq!(
+            schema["properties"]["func"],
+            serde_json::json!({ "const": "metadata" })
+        );
+        assert_eq!(schema["required"], serde_json::json!(["func", "value"]));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        let hash = |value: Value| content_hash(&value).unwrap();
+        let a = dict! { "func" => "heading", "level" => 1 }.into_value();
+        let b = dict! { "func" => "heading", "level" => 2 }.into_value();
+        assert_eq!(hash(a.clone()), hash(a.clone()));
+        assert_ne!(hash(a.clone()), hash(b));
+        assert_eq!(hash(a), "7889b80b0f8781ffe5817941b6bc2cc6".into_value());
+    }
+
+    #[test]
+    fn test_round_numbers() {
+        let value = array![
+            1.0000000000002,
+            Length::from(Abs::pt(2.34567)),
+            dict! { "x" => 0.125 },
+            3,
+        ];
+        assert_eq!(
+            round_numbers(value.into_value(), 2),
+            array![1.0, Length::from(Abs::pt(2.35)), dict! { "x" => 0.13 }, 3]
+                .into_value()
+        );
+    }
+
+    #[test]
+    fn test_stringify_numbers() {
+        let value = dict!["count" => 3, "ratio" => 0.1, "tags" => array![1, "two"]];
+        let json = serde_json::to_string(&stringify_numbers(value.into_value())).unwrap();
+        assert_eq!(json, r#"{"count":"3","ratio":"0.1","tags":["1","two"]}"#);
+    }
+
+    #[test]
+    fn test_group_key() {
+        assert_eq!(group_key(None), "null");
+        assert_eq!(group_key(Some(&Value::None)), "null");
+        assert_eq!(group_key(Some(&"a".into_value())), "a");
+        assert_eq!(group_key(Some(&2.into_value())), "2");
+        assert_eq!(group_key(Some(&TextElem::packed("b").into_value())), "b");
+        assert_eq!(group_key(Some(&ImageElem::elem().into_value())), "image");
+    }
+
+    #[test]
+    fn test_sort_by_field() {
+        let introspector = Introspector::default();
+        let number = |value: f64| MetadataElem::new(value.into_value()).pack();
+        let heading = HeadingElem::new(TextElem::packed("Missing")).pack();
+        let mut elements = vec![number(2.0), heading.clone(), number(1.5), number(10.0)];
+
+        sort_by_field(&mut elements, "value", &introspector, false);
+        assert_eq!(elements, [number(1.5), number(2.0), number(10.0), heading.clone()]);
+
+        sort_by_field(&mut elements, "value", &introspector, true);
+        assert_eq!(elements, [number(10.0), number(2.0), number(1.5), heading]);
+    }
+
+    #[test]
+    fn test_retrieve_in_memory() {
+        let text = "= A\n#pagebreak()\n= B\n#metadata(1)\n#pagebreak()\n#metadata(1)";
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let count = |args: &[&str]| retrieve_with(&world, &document, args).len();
+
+        assert_eq!(count(&["heading"]), 2);
+        assert_eq!(count(&["heading", "--pages", "2"]), 1);
+        assert_eq!(count(&["metadata"]), 2);
+        assert_eq!(count(&["metadata", "--unique"]), 1);
+        assert_eq!(count(&["metadata", "--pages", "3"]), 1);
+    }
+
+    #[test]
+    fn test_document_info() {
+        let text = "#set document(title: \"T\", author: (\"A\", \"B\"))\n\
+                    #set page(width: 100pt, height: 50pt)\nA\n#pagebreak()\nB";
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let info = document_info(&document);
+        assert_eq!(info.get("pages").unwrap(), &2.into_value());
+        assert_eq!(info.get("title").unwrap(), &"T".into_value());
+        assert_eq!(info.get("author").unwrap(), &array!["A", "B"].into_value());
+        assert_eq!(
+            info.get("sizes").unwrap(),
+            &array![
+                dict! { "width" => 100.0, "height" => 50.0 },
+                dict! { "width" => 100.0, "height" => 50.0 },
+            ]
+            .into_value()
+        );
+    }
+
+    #[test]
+    fn test_retrieve_by_depth() {
+        let text = "#figure(figure(rect(), caption: [Inner]), caption: [Outer])\n\
+                    #figure(rect())";
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let count = |args: &[&str]| retrieve_with(&world, &document, args).len();
+
+        assert_eq!(count(&["figure"]), 3);
+        assert_eq!(count(&["figure", "--max-depth", "0"]), 2);
+        assert_eq!(count(&["figure", "--min-depth", "1"]), 1);
+        assert_eq!(count(&["figure", "--min-depth", "2"]), 0);
+    }
+
+    #[test]
+    fn test_retrieve_hidden() {
+        let text = concat!(
+            "= Shown\n",
+            "#hide[= Hidden]\n",
+            "#hide(place(top, heading[Placed]))\n",
+            "#metadata(1)\n",
+            "#hide(metadata(2))\n",
+        );
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let count = |args: &[&str]| retrieve_with(&world, &document, args).len();
+
+        assert_eq!(count(&["heading"]), 3);
+        assert_eq!(count(&["heading", "--exclude-hidden"]), 1);
+        assert_eq!(count(&["metadata"]), 2);
+        assert_eq!(count(&["metadata", "--exclude-hidden"]), 2);
+    }
+
+    #[test]
+    fn test_retrieve_hidden_wrapper() {
+        // All headings stem from the same code and thus share a span.
+        let text = "#let h(x) = heading(x)\n#h[A]\n#hide(h[B])\n#h[C]";
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let texts = |args: &[&str]| {
+            retrieve_with(&world, &document, args)
+                .iter()
+                .map(|elem| elem.plain_text())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(texts(&["heading"]), ["A", "B", "C"]);
+        assert_eq!(texts(&["heading", "--exclude-hidden"]), ["A", "C"]);
+    }
+
+    #[test]
+    fn test_document_text() {
+        let text =
+            "#set text(size: 12pt)\n= Intro\nSome *bold* text \\ broken.\n\nNext one.";
+        let world = TestWorld::new(text);
+        assert_eq!(
+            document_text(&world).unwrap(),
+            "Intro\n\nSome bold text \n broken.\n\nNext one.\n"
+        );
+    }
+
+    #[test]
+    fn test_footnote_reference() {
+        let text = "= Intro\nA claim.#footnote[Source] More.\n\nOther #footnote[B].";
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let footnotes = document.introspector.query(&FootnoteElem::elem().select());
+        let references: Vec<_> = footnotes
+            .iter()
+            .map(|elem| footnote_reference(&world, &document.introspector, elem).unwrap())
+            .collect();
+        assert_eq!(references, ["A claim. More.".into_value(), "Other .".into_value()]);
+
+        let headings = document.introspector.query(&HeadingElem::elem().select());
+        let reference = footnote_reference(&world, &document.introspector, &headings[0]);
+        assert_eq!(reference.unwrap(), Value::None);
+    }
+
+    #[test]
+    fn test_footnote_reference_wrapper() {
+        let text = "#let note(x) = footnote(x)\nFirst *#note[A]*.\n\nSecond #note[B].";
+        let world = TestWorld::new(text);
+        let document = typst::compile(&world).output.unwrap();
+        let footnotes = document.introspector.query(&FootnoteElem::elem().select());
+        let references: Vec<_> = footnotes
+            .iter()
+            .map(|elem| footnote_reference(&world, &document.introspector, elem).unwrap())
+            .collect();
+        assert_eq!(references, ["First .".into_value(), "Second .".into_value()]);
+    }
+
+    #[test]
+    fn test_raw_texts() {
+        let text = concat!(
+            "```rust\nfn main() {}\n```\n",
+            "#strong[```py\nprint(1)\nprint(2)\n```]\n",
+            "Inline `code` without a language.\n",
+        );
+        let world = TestWorld::new(text);
+        assert_eq!(
+            raw_texts(&world).unwrap(),
+            array![
+                dict! { "lang" => "rust", "block" => true, "text" => "fn main() {}" },
+                dict! {
+                    "lang" => "py",
+                    "block" => true,
+                    "text" => "print(1)\nprint(2)",
+                },
+                dict! { "lang" => Value::None, "block" => false, "text" => "code" },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_metadata_values() {
+        let introspector = Introspector::default();
+        let values: Vec<_> = [
+            dict!["a" => 1].into_value(),
+            array![1, "two"].into_value(),
+            "three".into_value(),
+        ]
+        .into_iter()
+        .map(|value| {
+            let elem = MetadataElem::new(value).pack();
+            resolve_field(elem, "value", &introspector).unwrap()
+        })
+        .collect();
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(json, r#"[{"a":1},[1,"two"],"three"]"#);
+    }
+
+    #[test]
+    fn test_serialize_yaml_layout() {
+        let value = array![dict!["title" => "Intro", "level" => 1]].into_value();
+        let yaml = |layout| {
+            let mut out = vec![];
+            serialize(&value, SerializationFormat::Yaml, layout, &mut out).unwrap();
+            out
+        };
+        assert_eq!(yaml(Layout::Default), b"- title: Intro\n  level: 1\n");
+        assert_eq!(yaml(Layout::Pretty), yaml(Layout::Default));
+        assert_eq!(yaml(Layout::Compact), br#"[{"title":"Intro","level":1}]"#);
+
+        // Both styles must describe the same data.
+        let block: serde_yaml::Value =
+            serde_yaml::from_slice(&yaml(Layout::Default)).unwrap();
+        let flow: serde_yaml::Value =
+            serde_yaml::from_slice(&yaml(Layout::Compact)).unwrap();
+        assert_eq!(block, flow);
+
+        let layout = |args: &[&str]| {
+            let args = ["query", "-", "heading"].iter().chain(args);
+            QueryCommand::try_parse_from(args).map(|command| command.layout())
+        };
+        assert_eq!(layout(&[]).unwrap(), Layout::Default);
+        assert_eq!(layout(&["--compact"]).unwrap(), Layout::Compact);
+        assert!(layout(&["--pretty", "--compact"]).is_err());
+    }
+
+    #[test]
+    fn test_serialize_csv() {
+        let mut out = vec![];
+        let value = array![
+            dict!["title" => "Intro", "page" => 1],
+            dict!["title" => "Hello, world", "page" => 2],
+        ]
+        .into_value();
+        serialize(&value, SerializationFormat::Csv, Layout::Default, &mut out).unwrap();
+        assert_eq!(out, b"title,page\nIntro,1\n\"Hello, world\",2\n");
+
+        let differing = array![dict!["a" => 1], dict!["b" => 2]].into_value();
+        assert!(serialize(
+            &differing,
+            SerializationFormat::Csv,
+            Layout::Default,
+            &mut io::sink()
+        )
+        .is_err());
+        let nested = array![dict!["a" => array![1, 2]]].into_value();
+        assert!(serialize(
+            &nested,
+            SerializationFormat::Csv,
+            Layout::Default,
+            &mut io::sink()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_serialize_msgpack() {
+        let value = array![dict!["title" => "Intro", "level" => 1]].into_value();
+        let mut bytes = vec![];
+        serialize(&value, SerializationFormat::MsgPack, Layout::Default, &mut bytes)
+            .unwrap();
+        let decoded: Value = rmp_serde::from_slice(&bytes).unwrap();
+        assert_eq!(decoded, value);
+    }
+
+    #[test]
+    fn test_serialize_keeps_key_order() {
+        let value = dict!["b" => 1, "a" => dict!["d" => 3, "c" => 4]].into_value();
+        for format in [SerializationFormat::Json, SerializationFormat::Jsonl] {
+            let mut out = vec![];
+            serialize(&value, format, Layout::Default, &mut out).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert_eq!(out.trim_end(), r#"{"b":1,"a":{"d":3,"c":4}}"#);
         }
     }
+
+    #[test]
+    fn test_serialize_jsonl() {
+        let mut lines = vec![];
+        let value = array![dict!["level" => 1], "two"].into_value();
+        serialize(&value, SerializationFormat::Jsonl, Layout::Pretty, &mut lines)
+            .unwrap();
+        assert_eq!(lines, b"{\"level\":1}\n\"two\"\n");
+
+        let mut empty = vec![];
+        serialize(&Array::new(), SerializationFormat::Jsonl, Layout::Default, &mut empty)
+            .unwrap();
+        assert!(empty.is_empty());
+    }
 }
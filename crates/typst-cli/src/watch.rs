@@ -10,10 +10,14 @@ use codespan_reporting::term::{self, termcolor};
 use ecow::eco_format;
 use notify::{Event, RecommendedWatcher, RecursiveMode, Watcher as _};
 use same_file::is_same_file;
-use typst::diag::{bail, StrResult};
+use typst::diag::{bail, HintedStrResult, StrResult};
+use typst::foundations::dict;
 
-use crate::args::{CompileCommand, Input, Output};
+use crate::args::{CompileCommand, Input, Output, QueryCommand};
 use crate::compile::compile_once;
+use crate::query::{
+    emit_session, open_session, query_session, refresh_session, reset_phases, write_event,
+};
 use crate::timings::Timer;
 use crate::world::{SystemWorld, WorldCreationError};
 use crate::{print_error, terminal};
@@ -25,7 +29,7 @@ pub fn watch(mut timer: Timer, mut command: CompileCommand) -> StrResult<()> {
     };
 
     // Create a file system watcher.
-    let mut watcher = Watcher::new(output)?;
+    let mut watcher = Watcher::new(Some(output))?;
 
     // Create the world that serves sources, files, and fonts.
     // Additionally, if any files do not exist, wait until they do.
@@ -70,10 +74,71 @@ pub fn watch(mut timer: Timer, mut command: CompileCommand) -> StrResult<()> {
     }
 }
 
+/// Execute a watching query command.
+///
+/// The document is only recompiled when one of its dependencies changes. If
+/// just the selector file changes, the query is re-run against the previously
+/// compiled document.
+pub fn watch_query(command: &QueryCommand) -> HintedStrResult<()> {
+    // Stdin can only be read once, so there is nothing to watch.
+    if matches!(command.common.input, Input::Stdin) {
+        bail!("cannot watch input from stdin");
+    }
+
+    let output = match &command.output {
+        Some(Output::Path(path)) => Some(path.clone()),
+        _ => None,
+    };
+
+    let mut watcher = Watcher::new(output)?;
+    let mut session = open_session(command)?;
+
+    loop {
+        // A failing query shouldn't stop the watcher, just like a failing
+        // compilation doesn't.
+        if command.events {
+            if let Err(err) = emit_session(&mut session, command) {
+                write_event("error", dict! { "message" => err.message().clone() })?;
+            }
+        } else {
+            // Reset the timings before refreshing, so that `--envelope`
+            // reports the compilation.
+            reset_phases();
+            if refresh_session(&mut session, command)?.is_some() {
+                if let Err(err) = query_session(&mut session, command) {
+                    print_error(err.message())
+                        .map_err(|err| eco_format!("failed to print error ({err})"))?;
+                }
+            }
+        }
+
+        // Watch the selector file alongside the document's dependencies.
+        watcher.update(
+            session
+                .world_mut()
+                .dependencies()
+                .chain(command.selector_file.clone()),
+        )?;
+        watcher.wait()?;
+
+        let selector_changed_only = command.selector_file.as_ref().is_some_and(|file| {
+            !watcher.changed.is_empty()
+                && watcher
+                    .changed
+                    .iter()
+                    .all(|path| is_same_file(path, file).unwrap_or(false))
+        });
+
+        if !selector_changed_only {
+            session.invalidate();
+        }
+    }
+}
+
 /// Watches file system activity.
 struct Watcher {
-    /// The output file. We ignore any events for it.
-    output: PathBuf,
+    /// The output file, if any. We ignore any events for it.
+    output: Option<PathBuf>,
     /// The underlying watcher.
     watcher: RecommendedWatcher,
     /// Notify event receiver.
@@ -85,6 +150,9 @@ struct Watcher {
     /// A set of files that should be watched, but don't exist. We manually poll
     /// for those.
     missing: HashSet<PathBuf>,
+    /// The paths for which relevant events were observed during the last call
+    /// to `wait()`.
+    changed: HashSet<PathBuf>,
 }
 
 impl Watcher {
@@ -100,7 +168,7 @@ impl Watcher {
     const POLL_INTERVAL: Duration = Duration::from_millis(300);
 
     /// Create a new, blank watcher.
-    fn new(output: PathBuf) -> StrResult<Self> {
+    fn new(output: Option<PathBuf>) -> StrResult<Self> {
         // Setup file watching.
         let (tx, rx) = std::sync::mpsc::channel();
 
@@ -119,6 +187,7 @@ impl Watcher {
             watcher,
             watched: HashMap::new(),
             missing: HashSet::new(),
+            changed: HashSet::new(),
         })
     }
 
@@ -171,6 +240,7 @@ impl Watcher {
 
     /// Wait until there is a change to a watched path.
     fn wait(&mut self) -> StrResult<()> {
+        self.changed.clear();
         loop {
             // Wait for an initial event. If there are missing files, we need to
             // poll those regularly to check whether they are created, so we
@@ -217,12 +287,19 @@ impl Watcher {
                     }
                 }
 
-                relevant |= self.is_event_relevant(&event);
+                if self.is_event_relevant(&event) {
+                    relevant = true;
+                    self.changed.extend(event.paths);
+                }
             }
 
+            // Missing files that now exist count as changed, too.
+            self.changed
+                .extend(self.missing.iter().filter(|path| path.exists()).cloned());
+
             // If we found a relevant event or if any of the missing files now
             // exists, stop waiting.
-            if relevant || self.missing.iter().any(|path| path.exists()) {
+            if relevant || !self.changed.is_empty() {
                 return Ok(());
             }
         }
@@ -231,12 +308,14 @@ impl Watcher {
     /// Whether a watch event is relevant for compilation.
     fn is_event_relevant(&self, event: &notify::Event) -> bool {
         // Never recompile because the output file changed.
-        if event
-            .paths
-            .iter()
-            .all(|path| is_same_file(path, &self.output).unwrap_or(false))
-        {
-            return false;
+        if let Some(output) = &self.output {
+            if event
+                .paths
+                .iter()
+                .all(|path| is_same_file(path, output).unwrap_or(false))
+            {
+                return false;
+            }
         }
 
         match &event.kind {
@@ -268,7 +347,6 @@ impl Status {
     /// Clear the terminal and render the status message.
     pub fn print(&self, command: &CompileCommand) -> io::Result<()> {
         let output = command.output();
-        let timestamp = chrono::offset::Local::now().format("%H:%M:%S");
         let color = self.color();
 
         let mut out = terminal::out();
@@ -288,12 +366,27 @@ impl Status {
         writeln!(out, " {output}")?;
 
         writeln!(out)?;
-        writeln!(out, "[{timestamp}] {}", self.message())?;
+        self.write_message(&mut out)?;
         writeln!(out)?;
 
         out.flush()
     }
 
+    /// Render only the status message, without clearing the terminal.
+    ///
+    /// This is used by commands that report their compilation's progress
+    /// alongside other output, like `typst query --progress`.
+    pub fn print_message(&self) -> io::Result<()> {
+        let mut out = terminal::out();
+        self.write_message(&mut out)?;
+        out.flush()
+    }
+
+    fn write_message(&self, out: &mut impl Write) -> io::Result<()> {
+        let timestamp = chrono::offset::Local::now().format("%H:%M:%S");
+        writeln!(out, "[{timestamp}] {}", self.message())
+    }
+
     fn message(&self) -> String {
         match self {
             Self::Compiling => "compiling ...".into(),
@@ -111,8 +111,7 @@ impl SystemWorld {
         };
 
         let mut searcher = FontSearcher::new();
-        searcher
-            .search(&command.font_args.font_paths, command.font_args.ignore_system_fonts);
+        searcher.search_with(&command.font_args);
 
         let now = match command.creation_timestamp {
             Some(time) => Now::Fixed(time),
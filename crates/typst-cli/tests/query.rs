@@ -0,0 +1,25 @@
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn test_query_output_is_clean() {
+    let dir = tempfile::tempdir().unwrap();
+    let main = dir.path().join("main.typ");
+    fs::write(&main, "= A\n= B").unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_typst"))
+        .arg("query")
+        .arg("--ignore-system-fonts")
+        .arg(&main)
+        .arg("heading")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Nothing but the serialized elements is written, so scripts can consume
+    // the output as is.
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert_eq!(stderr, "");
+    let headings: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(headings.as_array().map(Vec::len), Some(2));
+}
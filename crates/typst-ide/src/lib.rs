@@ -3,11 +3,16 @@
 mod analyze;
 mod complete;
 mod jump;
+mod query;
 mod tooltip;
 
 pub use self::analyze::analyze_labels;
 pub use self::complete::{autocomplete, Completion, CompletionKind};
 pub use self::jump::{jump_from_click, jump_from_cursor, Jump};
+pub use self::query::{
+    element_field, eval_selector, expect_one, match_source_range, query, query_document,
+    QueryError, QuerySelector, QuerySession,
+};
 pub use self::tooltip::{tooltip, Tooltip};
 
 use std::fmt::Write;
@@ -91,8 +96,11 @@ fn summarize_font_family<'a>(variants: impl Iterator<Item = &'a FontInfo>) -> Ec
     detail
 }
 
-#[cfg(test)]
-mod tests {
+/// A world for testing, which is also available to the tests of other crates
+/// through the `testing` feature.
+#[cfg(any(test, feature = "testing"))]
+#[doc(hidden)]
+pub mod tests {
     use once_cell::sync::Lazy;
     use typst::diag::{FileError, FileResult};
     use typst::foundations::{Bytes, Datetime, Smart};
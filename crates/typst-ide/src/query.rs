@@ -0,0 +1,504 @@
+use std::borrow::Cow;
+use std::fmt::{self, Display, Formatter};
+use std::ops::Range;
+
+use comemo::Track;
+use ecow::{eco_format, EcoString, EcoVec};
+use typst::diag::SourceDiagnostic;
+use typst::eval::{eval_string, EvalMode};
+use typst::foundations::{Content, LocatableSelector, Scope, Value};
+use typst::model::Document;
+use typst::syntax::{FileId, Span};
+use typst::World;
+
+/// Compile the document and retrieve all elements that the selector, given as
+/// a string of Typst code, matches.
+///
+/// The selector is evaluated first, so that a malformed one is reported
+/// without compiling the document.
+pub fn query(world: &dyn World, selector: &str) -> Result<Vec<Content>, QueryError> {
+    let selector = eval_selector(world, selector, Scope::default())?;
+    let document = typst::compile(world).output.map_err(QueryError::Compile)?;
+    Ok(document.introspector.query(&selector.0).into_iter().collect())
+}
+
+/// Retrieve all elements of the document that the selector matches, in
+/// document order.
+///
+/// The selector is either a string of Typst code, which is evaluated first, or
+/// an already evaluated selector. This is what powers `typst query`, but it is
+/// independent of the CLI so that editors can run queries on documents they
+/// compiled themselves.
+pub fn query_document(
+    world: &dyn World,
+    document: &Document,
+    selector: &(impl QuerySelector + ?Sized),
+) -> Result<Vec<Content>, QueryError> {
+    let selector = selector.eval(world)?;
+    Ok(document.introspector.query(&selector.0).into_iter().collect())
+}
+
+/// A query session that keeps a world and the document compiled from it alive
+/// between queries.
+///
+/// Compilation is memoized across calls within a process, so a long-running
+/// process that issues many queries through one session only pays for what
+/// changed since the last compilation. When the sources didn't change at all,
+/// a repeated query of a large document skips compilation entirely and only
+/// takes as long as retrieving the elements, typically a few milliseconds
+/// instead of seconds.
+///
+/// The session doesn't notice by itself when the world's files change. Call
+/// [`invalidate`](Self::invalidate) then, so that the document is recompiled
+/// with the next query.
+pub struct QuerySession<W> {
+    /// The world that serves sources, files, and fonts.
+    world: W,
+    /// The last compiled document, if its compilation succeeded.
+    document: Option<Document>,
+    /// Whether the document needs to be recompiled before the next query.
+    stale: bool,
+}
+
+impl<W: World> QuerySession<W> {
+    /// Create a new session for a world. The document is compiled lazily,
+    /// with the first query.
+    pub fn new(world: W) -> Self {
+        Self { world, document: None, stale: true }
+    }
+
+    /// The world the document is compiled from.
+    pub fn world(&self) -> &W {
+        &self.world
+    }
+
+    /// Mutable access to the world the document is compiled from.
+    ///
+    /// The document isn't recompiled until the session is invalidated.
+    pub fn world_mut(&mut self) -> &mut W {
+        &mut self.world
+    }
+
+    /// The last compiled document, if its compilation succeeded.
+    pub fn document(&self) -> Option<&Document> {
+        self.document.as_ref()
+    }
+
+    /// Mark the document as outdated, for example because one of its
+    /// dependencies changed. It is recompiled with the next query.
+    pub fn invalidate(&mut self) {
+        self.stale = true;
+    }
+
+    /// Recompile the document if it is outdated or failed to compile, and
+    /// return it.
+    pub fn refresh(&mut self) -> Result<&Document, QueryError> {
+        match &mut self.document {
+            Some(document) if !self.stale => Ok(&*document),
+            slot => {
+                let document =
+                    typst::compile(&self.world).output.map_err(QueryError::Compile)?;
+                self.stale = false;
+                comemo::evict(10);
+                Ok(&*slot.insert(document))
+            }
+        }
+    }
+
+    /// Recompile the document with a custom function if it is outdated, and
+    /// return it.
+    ///
+    /// This allows preparing the world before compilation and reporting
+    /// diagnostics in a custom way. The function returns `None` if the
+    /// document failed to compile, in which case it is only compiled again
+    /// once the session was invalidated.
+    pub fn refresh_with<E>(
+        &mut self,
+        compile: impl FnOnce(&mut W) -> Result<Option<Document>, E>,
+    ) -> Result<Option<&Document>, E> {
+        if self.stale {
+            self.document = compile(&mut self.world)?;
+            self.stale = false;
+            comemo::evict(10);
+        }
+        Ok(self.document.as_ref())
+    }
+
+    /// Retrieve all elements of the document that the selector matches,
+    /// recompiling the document first if needed.
+    ///
+    /// A selector given as Typst code is evaluated before compiling, so that a
+    /// malformed one doesn't waste a compilation.
+    pub fn query(
+        &mut self,
+        selector: &(impl QuerySelector + ?Sized),
+    ) -> Result<Vec<Content>, QueryError> {
+        let selector = selector.eval(&self.world)?;
+        self.refresh()?;
+        let document = self.document.as_ref().expect("document was just compiled");
+        query_document(&self.world, document, &*selector)
+    }
+}
+
+/// A selector that a document can be queried with.
+pub trait QuerySelector {
+    /// Evaluate the selector, if it isn't already.
+    fn eval(&self, world: &dyn World) -> Result<Cow<'_, LocatableSelector>, QueryError>;
+}
+
+impl QuerySelector for str {
+    fn eval(&self, world: &dyn World) -> Result<Cow<'_, LocatableSelector>, QueryError> {
+        eval_selector(world, self, Scope::default()).map(Cow::Owned)
+    }
+}
+
+impl QuerySelector for LocatableSelector {
+    fn eval(&self, _: &dyn World) -> Result<Cow<'_, LocatableSelector>, QueryError> {
+        Ok(Cow::Borrowed(self))
+    }
+}
+
+/// Evaluate a selector given as a string of Typst code.
+///
+/// The `scope` holds additional bindings the selector may refer to. This
+/// doesn't need a compiled document, so a malformed selector can be reported
+/// before spending time on compilation.
+pub fn eval_selector(
+    world: &dyn World,
+    selector: &str,
+    scope: Scope,
+) -> Result<LocatableSelector, QueryError> {
+    eval_string(world.track(), selector, Span::detached(), EvalMode::Code, scope)
+        .map_err(|errors| {
+            // Element functions don't have the selector methods, so
+            // `heading.before(..)` fails while `heading.where(..).before(..)`
+            // works.
+            let positional = errors.iter().any(|error| {
+                error.message.ends_with("does not contain field `before`")
+                    || error.message.ends_with("does not contain field `after`")
+            });
+
+            let mut message = EcoString::from("failed to evaluate selector");
+            for (i, error) in errors.into_iter().enumerate() {
+                message.push_str(if i == 0 { ": " } else { ", " });
+                message.push_str(&error.message);
+            }
+            if positional {
+                message
+                    .push_str(" (try wrapping the element function in `selector(..)`)");
+            }
+            QueryError::SelectorParse(message)
+        })?
+        .cast::<LocatableSelector>()
+        .map_err(|err| QueryError::SelectorParse(err.message().clone()))
+}
+
+/// Ensure that exactly one element was retrieved and return it.
+pub fn expect_one(elements: &[Content]) -> Result<&Content, QueryError> {
+    match elements {
+        [] => Err(QueryError::NoMatch),
+        [elem] => Ok(elem),
+        _ => Err(QueryError::TooManyMatches(elements.len())),
+    }
+}
+
+/// Retrieve a field of an element by name.
+pub fn element_field(elem: &Content, field: &str) -> Result<Value, QueryError> {
+    elem.field_by_name(field)
+        .map_err(|message| QueryError::FieldMissing { field: field.into(), message })
+}
+
+/// Find the file and byte range in it that an element was created from.
+///
+/// Returns `None` if the element doesn't stem from a source file, for example
+/// because it was synthesized by Typst itself.
+pub fn match_source_range(
+    world: &dyn World,
+    content: &Content,
+) -> Option<(FileId, Range<usize>)> {
+    let span = content.span();
+    let id = span.id()?;
+    let range = world.source(id).ok()?.range(span)?;
+    Some((id, range))
+}
+
+/// An error that occurs while querying a document.
+#[derive(Debug, Clone)]
+pub enum QueryError {
+    /// The selector could not be evaluated or is not a selector.
+    SelectorParse(EcoString),
+    /// The document failed to compile.
+    Compile(EcoVec<SourceDiagnostic>),
+    /// No element matched, but exactly one was expected.
+    NoMatch,
+    /// Multiple elements matched, but exactly one was expected.
+    TooManyMatches(usize),
+    /// An element does not have a requested field.
+    FieldMissing { field: EcoString, message: EcoString },
+}
+
+impl Display for QueryError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SelectorParse(message) => f.write_str(message),
+            Self::Compile(errors) => {
+                f.write_str("failed to compile document")?;
+                for (i, error) in errors.iter().enumerate() {
+                    f.write_str(if i == 0 { ": " } else { ", " })?;
+                    f.write_str(&error.message)?;
+                }
+                Ok(())
+            }
+            Self::NoMatch => f.write_str("no elements matched selector"),
+            Self::TooManyMatches(n) => {
+                write!(f, "expected exactly one element, found {n}")
+            }
+            Self::FieldMissing { message, .. } => f.write_str(message),
+        }
+    }
+}
+
+impl From<QueryError> for EcoString {
+    fn from(err: QueryError) -> Self {
+        eco_format!("{err}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use typst::foundations::{Scope, Value};
+    use typst::syntax::Source;
+    use typst::World;
+
+    use super::{
+        eval_selector, expect_one, match_source_range, query, query_document, QueryError,
+        QuerySession,
+    };
+    use crate::tests::TestWorld;
+
+    #[track_caller]
+    fn test(text: &str, selector: &str, expected: &[&str]) {
+        let world = TestWorld::new(text);
+        let doc = typst::compile(&world).output.unwrap();
+        let elems = query_document(&world, &doc, selector).unwrap();
+        let names: Vec<_> = elems.iter().map(|elem| elem.func().name()).collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn test_query_elements() {
+        test("= A\n= B\n#figure[C]", "heading", &["heading", "heading"]);
+        test("= A\n#metadata(1) <a>", "<a>", &["metadata"]);
+        test("= A", "figure", &[]);
+    }
+
+    #[test]
+    fn test_query_where() {
+        let text = "= A\n== B\n= C\n#figure(rect(), kind: image)\n#figure(table[D])";
+        test(text, "heading.where(level: 1)", &["heading", "heading"]);
+        test(text, "heading.where(level: 3)", &[]);
+        test(text, "figure.where(kind: image)", &["figure"]);
+        test(text, "figure.where(kind: table)", &["figure"]);
+        test(text, "figure", &["figure", "figure"]);
+    }
+
+    #[test]
+    fn test_query_where_value_types() {
+        let text = concat!(
+            "#set heading(numbering: \"1.\")\n",
+            "= A\n",
+            "#heading(outlined: false, level: 2)[B]\n",
+            "#metadata(1)\n",
+            "#metadata(true)\n",
+            "#metadata(\"one\")\n",
+            "#metadata(2pt)\n",
+            "#metadata(50%)\n",
+        );
+
+        // Integers and booleans.
+        test(text, "heading.where(level: 2)", &["heading"]);
+        test(text, "heading.where(outlined: false)", &["heading"]);
+        test(text, "metadata.where(value: 1)", &["metadata"]);
+        test(text, "metadata.where(value: 1.0)", &["metadata"]);
+        test(text, "metadata.where(value: true)", &["metadata"]);
+        test(text, "metadata.where(value: false)", &[]);
+
+        // Strings, also when the field is cast from one.
+        test(text, "metadata.where(value: \"one\")", &["metadata"]);
+        test(text, "heading.where(numbering: \"1.\")", &["heading", "heading"]);
+        test(text, "heading.where(numbering: \"1\")", &[]);
+
+        // Lengths and ratios, also as relative lengths.
+        test(text, "metadata.where(value: 2pt)", &["metadata"]);
+        test(text, "metadata.where(value: 2pt + 0%)", &["metadata"]);
+        test(text, "metadata.where(value: 50%)", &["metadata"]);
+        test(text, "metadata.where(value: 3pt)", &[]);
+    }
+
+    #[test]
+    fn test_query_where_outlined_supplement() {
+        let text = concat!(
+            "#figure(rect(), caption: [A])\n",
+            "#figure(rect(), caption: [B], outlined: false)\n",
+            "#figure(rect(), caption: [C], supplement: [Plate])\n",
+            "= D\n",
+            "#heading(supplement: [Chapter])[E]\n",
+        );
+
+        // Booleans, also when the field has its default value.
+        test(text, "figure.where(outlined: true)", &["figure", "figure"]);
+        test(text, "figure.where(outlined: false)", &["figure"]);
+
+        // Content, also when it was synthesized from the default.
+        test(text, "figure.where(supplement: [Plate])", &["figure"]);
+        test(text, "heading.where(supplement: [Chapter])", &["heading"]);
+        test(text, "heading.where(supplement: [Section])", &["heading"]);
+        test(text, "heading.where(supplement: [Part])", &[]);
+    }
+
+    #[test]
+    fn test_query_before_after() {
+        let world = TestWorld::new("= A <a>\n= B\n= C <c>\n= D");
+        let doc = typst::compile(&world).output.unwrap();
+        let texts = |selector: &str| {
+            query_document(&world, &doc, selector)
+                .unwrap()
+                .iter()
+                .map(|elem| elem.plain_text())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(texts("selector(heading).before(<c>)"), ["A", "B", "C"]);
+        assert_eq!(texts("selector(heading).after(<c>, inclusive: false)"), ["D"]);
+        assert_eq!(
+            texts("heading.where(level: 1).after(<a>).before(<c>)"),
+            ["A", "B", "C"]
+        );
+        let between = concat!(
+            "selector(heading)",
+            ".after(<a>, inclusive: false)",
+            ".before(<c>, inclusive: false)",
+        );
+        assert_eq!(texts(between), ["B"]);
+
+        let Err(QueryError::SelectorParse(message)) =
+            query_document(&world, &doc, "heading.after(<a>)")
+        else {
+            panic!("expected the selector to fail");
+        };
+        assert!(message.contains("selector(..)"));
+    }
+
+    #[test]
+    fn test_query_standard_library() {
+        // Selectors are evaluated with the standard library in scope.
+        let text = "= A\n== B\n=== C";
+        test(text, "heading.where(level: calc.min(2, 3))", &["heading"]);
+        test(
+            text,
+            "heading.where(level: int(sys.inputs.at(\"x\", default: 3)))",
+            &["heading"],
+        );
+    }
+
+    #[test]
+    fn test_query_bare_label() {
+        // Labels are valid code, so they need no special treatment.
+        test("= A <intro>\n= B", "<intro>", &["heading"]);
+        test("= A <fig:intro>\n= B", " <fig:intro> ", &["heading"]);
+    }
+
+    #[test]
+    fn test_query_metadata_value() {
+        let world = TestWorld::new("#metadata((a: 1)) <info>");
+        let doc = typst::compile(&world).output.unwrap();
+        let elems = query_document(&world, &doc, "<info>").unwrap();
+        assert_eq!(elems.len(), 1);
+        let value = elems[0].get_by_name("value").unwrap();
+        assert!(matches!(value, Value::Dict(_)));
+    }
+
+    #[test]
+    fn test_query_evaluated_selector() {
+        let world = TestWorld::new("= A\n#figure[B]\n= C");
+        let doc = typst::compile(&world).output.unwrap();
+        let selector = eval_selector(&world, "heading", Scope::default()).unwrap();
+        let elems = query_document(&world, &doc, &selector).unwrap();
+        assert_eq!(elems, query_document(&world, &doc, "heading").unwrap());
+        assert_eq!(elems.len(), 2);
+    }
+
+    #[test]
+    fn test_query_session() {
+        let mut session = QuerySession::new(TestWorld::new("= A"));
+        assert_eq!(session.query("heading").unwrap().len(), 1);
+        assert!(matches!(
+            session.query("heading.where("),
+            Err(QueryError::SelectorParse(_))
+        ));
+
+        // The document is kept until the session is invalidated.
+        session.world_mut().main = Source::detached("= A\n= B");
+        assert_eq!(session.query("heading").unwrap().len(), 1);
+        session.invalidate();
+        assert_eq!(session.query("heading").unwrap().len(), 2);
+
+        let mut broken = QuerySession::new(TestWorld::new("#panic()"));
+        assert!(matches!(broken.query("heading"), Err(QueryError::Compile(_))));
+        assert!(broken.document().is_none());
+    }
+
+    #[test]
+    fn test_query_invalid_selector() {
+        let world = TestWorld::new("= A");
+        let doc = typst::compile(&world).output.unwrap();
+        assert!(query_document(&world, &doc, "heading.where(").is_err());
+        assert!(query_document(&world, &doc, "1 + 2").is_err());
+    }
+
+    #[test]
+    fn test_query_errors() {
+        let world = TestWorld::new("= A\n= B\n#metadata(1) <m>");
+        assert!(matches!(
+            query(&world, "heading.where("),
+            Err(QueryError::SelectorParse(_))
+        ));
+        assert!(matches!(query(&world, "1 + 2"), Err(QueryError::SelectorParse(_))));
+
+        let elems = query(&world, "heading").unwrap();
+        assert!(matches!(expect_one(&elems), Err(QueryError::TooManyMatches(2))));
+        assert!(matches!(expect_one(&elems[..0]), Err(QueryError::NoMatch)));
+
+        let broken = TestWorld::new("#panic()");
+        assert!(matches!(query(&broken, "heading"), Err(QueryError::Compile(_))));
+    }
+
+    #[test]
+    fn test_match_source_range() {
+        let text = "= A\n#figure(rect())";
+        let world = TestWorld::new(text);
+        let elems = query(&world, "selector(heading).or(figure)").unwrap();
+        let ranges: Vec<_> = elems
+            .iter()
+            .map(|elem| {
+                let (id, range) = match_source_range(&world, elem).unwrap();
+                assert_eq!(id, world.main());
+                &text[range]
+            })
+            .collect();
+        assert_eq!(ranges, ["= A", "figure(rect())"]);
+    }
+
+    #[test]
+    fn test_eval_selector_with_scope() {
+        let world = TestWorld::new("= A\n== B");
+        let doc = typst::compile(&world).output.unwrap();
+        let mut scope = Scope::new();
+        let h2 = eval_selector(&world, "heading.where(level: 2)", Scope::new()).unwrap();
+        scope.define("h2", h2.0);
+        let selector = eval_selector(&world, "h2", scope).unwrap();
+        let elems = doc.introspector.query(&selector.0);
+        assert_eq!(elems.len(), 1);
+        assert!(eval_selector(&world, "h2", Scope::new()).is_err());
+    }
+}
@@ -194,6 +194,19 @@ impl BibliographyElem {
         }
         vec
     }
+
+    /// The rendered references of the document's bibliography in the order
+    /// they are listed, each alongside its key and optional prefix.
+    pub fn references(
+        world: Tracked<dyn World + '_>,
+        introspector: Tracked<Introspector>,
+    ) -> StrResult<Vec<(EcoString, Option<Content>, Content)>> {
+        let works = Works::generate(world, introspector)?;
+        match &works.references {
+            Some(references) => Ok(references.clone()),
+            None => bail!("CSL style is not suitable for bibliographies"),
+        }
+    }
 }
 
 impl Synthesize for Packed<BibliographyElem> {
@@ -232,9 +245,9 @@ impl Show for Packed<BibliographyElem> {
             .at(span)?;
 
         let row_gutter = ParElem::spacing_in(styles).into();
-        if references.iter().any(|(prefix, _)| prefix.is_some()) {
+        if references.iter().any(|(_, prefix, _)| prefix.is_some()) {
             let mut cells = vec![];
-            for (prefix, reference) in references {
+            for (_, prefix, reference) in references {
                 cells.push(GridChild::Item(GridItem::Cell(
                     Packed::new(GridCell::new(prefix.clone().unwrap_or_default()))
                         .spanned(span),
@@ -254,7 +267,7 @@ impl Show for Packed<BibliographyElem> {
                     .spanned(self.span()),
             );
         } else {
-            for (_, reference) in references {
+            for (_, _, reference) in references {
                 seq.push(VElem::new(row_gutter).with_weakness(3).pack());
                 seq.push(reference.clone());
             }
@@ -541,9 +554,10 @@ impl Repr for CslStyle {
 pub(super) struct Works {
     /// Maps from the location of a citation group to its rendered content.
     pub citations: HashMap<Location, SourceResult<Content>>,
-    /// Lists all references in the bibliography, with optional prefix, or
-    /// `None` if the citation style can't be used for bibliographies.
-    pub references: Option<Vec<(Option<Content>, Content)>>,
+    /// Lists all references in the bibliography, with their key and optional
+    /// prefix, or `None` if the citation style can't be used for
+    /// bibliographies.
+    pub references: Option<Vec<(EcoString, Option<Content>, Content)>>,
     /// Whether the bibliography should have hanging indent.
     pub hanging_indent: bool,
 }
@@ -796,7 +810,7 @@ impl<'a> Generator<'a> {
     fn display_references(
         &self,
         rendered: &hayagriva::Rendered,
-    ) -> Option<Vec<(Option<Content>, Content)>> {
+    ) -> Option<Vec<(EcoString, Option<Content>, Content)>> {
         let rendered = rendered.bibliography.as_ref()?;
 
         // Determine for each citation key where it first occurred, so that we
@@ -844,7 +858,7 @@ impl<'a> Generator<'a> {
             // we can link to the bibliography entry.
             prefix.as_mut().unwrap_or(&mut reference).set_location(backlink);
 
-            output.push((prefix, reference));
+            output.push((item.key.as_str().into(), prefix, reference));
         }
 
         Some(output)